@@ -0,0 +1,348 @@
+//! A blind as the app knows it — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! Everything needed to speak for one motor: its remote's address, where the
+//! rolling code is up to and how frames are built. One cat, one blind :3
+
+use heapless::String;
+
+use crate::protocol::{
+    FrameLength, KeyPolicy, Press, SomfyAddress, SomfyCommand, SomfyFrame, SomfyTimings,
+};
+use crate::radio::{Preset, SOMFY_FREQUENCY_HZ, is_valid_frequency};
+
+pub const MAX_NAME_LEN: usize = 20;
+
+pub const MAX_ROOM_LEN: usize = 20;
+
+/// Frames per short press, like a genuine remote.
+pub const DEFAULT_REPEATS: u8 = 4;
+
+/// Most frames per short press a blind can ask for.
+pub const MAX_REPEATS: u8 = 20;
+
+/// Most rolling codes reserved in one go. Motors accept codes a fair way
+/// ahead of the last one they heard, so skipping a block after a crash is
+/// harmless as long as it stays well inside that window.
+pub const MAX_CODE_BLOCK: u16 = 32;
+
+/// What the motor moves. Only a label — every kind speaks the same protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum BlindType {
+    #[default]
+    Roller,
+    Venetian,
+    Awning,
+    Shutter,
+    Curtain,
+    Other,
+}
+
+impl BlindType {
+    const ALL: [BlindType; 6] = [
+        BlindType::Roller,
+        BlindType::Venetian,
+        BlindType::Awning,
+        BlindType::Shutter,
+        BlindType::Curtain,
+        BlindType::Other,
+    ];
+
+    /// Parse the state file spelling, e.g. `roller` or `awning`.
+    pub fn parse(text: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.encode() == text)
+    }
+
+    /// The state file spelling, the inverse of `parse`.
+    pub fn encode(self) -> &'static str {
+        match self {
+            BlindType::Roller => "roller",
+            BlindType::Venetian => "venetian",
+            BlindType::Awning => "awning",
+            BlindType::Shutter => "shutter",
+            BlindType::Curtain => "curtain",
+            BlindType::Other => "other",
+        }
+    }
+}
+
+/// How long the blind takes to run fully open or fully closed.
+///
+/// Zero means never timed.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct TravelTimes {
+    pub up_ms: u32,
+    pub down_ms: u32,
+}
+
+/// A single blind's persisted state — name, address, rolling code and how its frames are built.
+pub struct SomfyBlind {
+    pub name: String<MAX_NAME_LEN>,
+    pub address: SomfyAddress,
+    pub rolling_code: u16,
+    pub frame_length: FrameLength,
+    pub key_policy: KeyPolicy,
+    pub timings: SomfyTimings,
+    /// Carrier frequency in Hz — 433.42 MHz for genuine Somfy, 433.92 MHz for some clones.
+    pub frequency: u32,
+    pub preset: Preset,
+    /// Where the blind is, e.g. "Kitchen"; empty if not set.
+    pub room: String<MAX_ROOM_LEN>,
+    pub blind_type: BlindType,
+    /// Frames sent for a short press.
+    pub repeats: u8,
+    /// The position stored under My, in percent closed, if known.
+    pub favourite: Option<u8>,
+    pub travel: TravelTimes,
+    /// Codes from `rolling_code` on that the state file already counts as
+    /// used. Only lives in memory; a loaded blind starts with none.
+    pub reserved: u16,
+}
+
+/// The code `count` presses after `code`, skipping zero like the C app.
+fn code_after(mut code: u16, count: u16) -> u16 {
+    for _ in 0..count {
+        code = code.wrapping_add(1);
+        if code == 0 {
+            code = 1;
+        }
+    }
+    code
+}
+
+impl SomfyBlind {
+    /// A blind with a fresh remote: genuine-style keys and frames, and no
+    /// details filled in yet.
+    pub fn new(name: String<MAX_NAME_LEN>, address: SomfyAddress, rolling_code: u16) -> Self {
+        SomfyBlind {
+            name,
+            address,
+            rolling_code,
+            frame_length: FrameLength::Standard,
+            key_policy: KeyPolicy::Rolling,
+            timings: SomfyTimings::DEFAULT,
+            frequency: SOMFY_FREQUENCY_HZ,
+            preset: Preset::Ook650Async,
+            room: String::new(),
+            blind_type: BlindType::default(),
+            repeats: DEFAULT_REPEATS,
+            favourite: None,
+            travel: TravelTimes::default(),
+            reserved: 0,
+        }
+    }
+
+    /// Build the frame this blind's remote would send for `command` right now.
+    pub fn frame(&self, command: SomfyCommand) -> SomfyFrame {
+        let frame = SomfyFrame::new(command, self.rolling_code, self.address)
+            .with_key(self.key_policy.key(self.rolling_code));
+        match self.frame_length {
+            FrameLength::Standard => frame,
+            // Only the 56-bit payload carries anything we know how to fill in
+            FrameLength::Extended => frame.with_extension([0; 3]),
+        }
+    }
+
+    /// A blind that takes over from a remote last heard sending `frame`.
+    ///
+    /// It starts at the code after the captured one and keeps the remote's key
+    /// style and frame length, so the motor sees the next press it expects.
+    pub fn continuing(name: String<MAX_NAME_LEN>, frame: SomfyFrame) -> Self {
+        let mut blind = SomfyBlind::new(name, frame.address(), frame.rolling_code());
+        blind.frame_length = frame.length();
+        blind.key_policy = KeyPolicy::infer(frame.key(), frame.rolling_code());
+        blind.advance_rolling_code();
+        blind
+    }
+
+    /// `press` with this blind's repeat count for short and held presses.
+    ///
+    /// Timed holds (Prog, My) are about how long, not how many, so they're kept.
+    pub fn press(&self, press: Press) -> Press {
+        match press {
+            Press::Frames(_) => Press::Frames(self.repeats),
+            Press::Held(_) => Press::Held(self.repeats),
+            Press::HoldMs(ms) => Press::HoldMs(ms),
+        }
+    }
+
+    /// Take on the radio settings a remote was captured with, where known.
+    ///
+    /// Frequencies the CC1101 can't tune to are ignored rather than stored.
+    pub fn tune(&mut self, frequency: Option<u32>, preset: Option<Preset>) {
+        if let Some(frequency) = frequency.filter(|&hz| is_valid_frequency(hz)) {
+            self.frequency = frequency;
+        }
+        if let Some(preset) = preset {
+            self.preset = preset;
+        }
+    }
+
+    /// Move on to the next rolling code once the current one has been used.
+    ///
+    /// Zero is skipped on wrap-around, matching the C app.
+    pub fn advance_rolling_code(&mut self) {
+        self.rolling_code = code_after(self.rolling_code, 1);
+        self.reserved = self.reserved.saturating_sub(1);
+    }
+
+    /// Jump `count` codes ahead without sending them, e.g. past codes that
+    /// may have gone out after the state file was last written.
+    pub fn skip_rolling_codes(&mut self, count: u16) {
+        self.rolling_code = code_after(self.rolling_code, count);
+        self.reserved = self.reserved.saturating_sub(count);
+    }
+
+    /// Make sure the current rolling code is reserved before it goes on air.
+    ///
+    /// Returns true if a fresh block of `block` codes had to be reserved, in
+    /// which case the state must be saved — and the save must succeed — before
+    /// sending. Until the block runs out, sends need no write at all.
+    pub fn reserve_rolling_code(&mut self, block: u16) -> bool {
+        if self.reserved > 0 {
+            return false;
+        }
+        self.reserved = block.clamp(1, MAX_CODE_BLOCK);
+        true
+    }
+
+    /// Hand back the codes reserved but never sent, e.g. on a clean exit.
+    ///
+    /// The next save then stores the current code rather than skipping ahead.
+    pub fn release_reservation(&mut self) {
+        self.reserved = 0;
+    }
+
+    /// The rolling code to write to the state file: the first one past the
+    /// reservation, so a crash mid-block can never bring back a code that may
+    /// already have been sent. Loading it back is all the recovery needed.
+    pub fn stored_rolling_code(&self) -> u16 {
+        code_after(self.rolling_code, self.reserved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blind(rolling_code: u16) -> SomfyBlind {
+        let name = String::try_from("Kitchen").unwrap();
+        SomfyBlind::new(name, SomfyAddress::new(0x1A2B3C).unwrap(), rolling_code)
+    }
+
+    #[test]
+    fn test_frame_uses_key_policy_and_length() {
+        let mut b = blind(0x0041);
+        let frame = b.frame(SomfyCommand::Up);
+        assert_eq!(frame.key(), 0xA1);
+        assert_eq!(frame.rolling_code(), 0x0041);
+        assert_eq!(frame.length(), FrameLength::Standard);
+
+        b.frame_length = FrameLength::Extended;
+        assert_eq!(b.frame(SomfyCommand::Up).extension(), Some([0; 3]));
+    }
+
+    #[test]
+    fn test_advance_rolling_code_skips_zero() {
+        let mut b = blind(0xFFFE);
+        b.advance_rolling_code();
+        assert_eq!(b.rolling_code, 0xFFFF);
+        b.advance_rolling_code();
+        assert_eq!(b.rolling_code, 1);
+    }
+
+    #[test]
+    fn test_reservation_covers_a_block_of_sends() {
+        let mut b = blind(10);
+        assert!(b.reserve_rolling_code(3), "nothing reserved yet, must save");
+        assert_eq!(b.stored_rolling_code(), 13);
+
+        for expected in [11, 12, 13] {
+            assert!(!b.reserve_rolling_code(3), "still inside the block");
+            b.advance_rolling_code();
+            assert_eq!(b.rolling_code, expected);
+            // The file still points past every code that may have been sent
+            assert_eq!(b.stored_rolling_code(), 13);
+        }
+        assert_eq!(b.reserved, 0);
+        assert!(b.reserve_rolling_code(3), "block used up, must save again");
+    }
+
+    #[test]
+    fn test_reservation_survives_a_crash() {
+        let mut b = blind(10);
+        b.reserve_rolling_code(4);
+        // The state file is written here, then one code goes out
+        let on_disk = b.stored_rolling_code();
+        b.advance_rolling_code();
+
+        // Crash: the reloaded blind carries on past everything reserved
+        let reloaded = blind(on_disk);
+        assert_eq!(reloaded.rolling_code, 14);
+        assert!(reloaded.rolling_code > b.rolling_code);
+    }
+
+    #[test]
+    fn test_skip_rolling_codes() {
+        let mut b = blind(0xFFF0);
+        b.skip_rolling_codes(0x20);
+        assert_eq!(b.rolling_code, 0x0011, "zero is skipped on the way round");
+        b.skip_rolling_codes(0);
+        assert_eq!(b.rolling_code, 0x0011);
+    }
+
+    #[test]
+    fn test_reservation_wraps_and_releases() {
+        let mut b = blind(0xFFFE);
+        b.reserve_rolling_code(3);
+        assert_eq!(b.stored_rolling_code(), 2, "zero is skipped on the way round");
+
+        b.advance_rolling_code();
+        b.release_reservation();
+        assert_eq!(b.stored_rolling_code(), 0xFFFF);
+
+        b.reserve_rolling_code(1000);
+        assert_eq!(b.reserved, MAX_CODE_BLOCK);
+        b.reserve_rolling_code(0);
+        assert_eq!(b.reserved, MAX_CODE_BLOCK, "a live block is kept");
+    }
+
+    #[test]
+    fn test_press_uses_blind_repeats() {
+        let mut b = blind(1);
+        b.repeats = 2;
+        assert_eq!(b.press(Press::Frames(4)), Press::Frames(2));
+        assert_eq!(b.press(Press::Held(4)), Press::Held(2));
+        assert_eq!(b.press(Press::HoldMs(3000)), Press::HoldMs(3000));
+    }
+
+    #[test]
+    fn test_blind_type_round_trips() {
+        for kind in BlindType::ALL {
+            assert_eq!(BlindType::parse(kind.encode()), Some(kind));
+        }
+        assert_eq!(BlindType::parse("Roller"), None);
+    }
+
+    #[test]
+    fn test_tune_ignores_unusable_frequencies() {
+        let mut b = blind(1);
+        b.tune(Some(433_920_000), Some(Preset::Ook270Async));
+        assert_eq!(b.frequency, 433_920_000);
+        assert_eq!(b.preset, Preset::Ook270Async);
+
+        b.tune(Some(2_400_000_000), None);
+        assert_eq!(b.frequency, 433_920_000);
+        assert_eq!(b.preset, Preset::Ook270Async);
+    }
+
+    #[test]
+    fn test_continuing_picks_up_after_capture() {
+        let address = SomfyAddress::new(0x1A2B3C).unwrap();
+        let frame = SomfyFrame::new(SomfyCommand::Down, 0x0042, address).with_key(0xA2);
+        let b = SomfyBlind::continuing(String::new(), frame);
+        assert_eq!(b.address, frame.address());
+        assert_eq!(b.rolling_code, 0x0043);
+        assert_eq!(b.key_policy, KeyPolicy::Rolling);
+        assert_eq!(b.frame(SomfyCommand::Up).key(), 0xA3);
+    }
+}
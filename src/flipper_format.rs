@@ -0,0 +1,338 @@
+//! FlipperFormat text files — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! The firmware's `flipper_format_*` calls read and write `Key: value` lines
+//! under a `Filetype`/`Version` header. This speaks the same text so the
+//! files the app keeps can be built and checked on the host, byte for byte
+//! against what the C app writes. Reads search forward from where the last
+//! one stopped, exactly like the firmware, so files with keys we don't know
+//! (or keys the C app doesn't know) read the same either way. A cat reads
+//! the room left to right, too :3
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+const FILETYPE_KEY: &str = "Filetype";
+const VERSION_KEY: &str = "Version";
+
+/// Where a `Reader` gets its lines: a string in memory, or a file read a
+/// chunk at a time so a long one never has to fit in RAM.
+pub trait LineSource {
+    /// Move on to the next line; false once there are none left.
+    fn advance(&mut self) -> bool;
+
+    /// The line `advance` moved to, without its `\n`.
+    fn line(&self) -> &str;
+
+    /// Where the next line starts, for `seek`.
+    fn tell(&self) -> usize;
+
+    /// Go back (or forward) to a position from `tell`.
+    fn seek(&mut self, position: usize);
+}
+
+/// The lines of a file already in memory.
+pub struct TextLines<T> {
+    text: T,
+    line: Range<usize>,
+    /// Byte offset of the next line to look at.
+    position: usize,
+}
+
+impl<T: AsRef<str>> TextLines<T> {
+    pub fn new(text: T) -> Self {
+        Self { text, line: 0..0, position: 0 }
+    }
+}
+
+impl<T: AsRef<str>> LineSource for TextLines<T> {
+    fn advance(&mut self) -> bool {
+        let text = self.text.as_ref();
+        if self.position >= text.len() {
+            return false;
+        }
+        let rest = &text[self.position..];
+        let (end, next) = match rest.find('\n') {
+            Some(end) => (self.position + end, self.position + end + 1),
+            None => (text.len(), text.len()),
+        };
+        self.line = self.position..end;
+        self.position = next;
+        true
+    }
+
+    fn line(&self) -> &str {
+        &self.text.as_ref()[self.line.clone()]
+    }
+
+    fn tell(&self) -> usize {
+        self.position
+    }
+
+    fn seek(&mut self, position: usize) {
+        self.position = position.min(self.text.as_ref().len());
+    }
+}
+
+/// The value on `line` if it's a `key` line.
+fn line_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    if line.starts_with('#') {
+        return None;
+    }
+    match line.split_once(':') {
+        Some((name, value)) if name == key => Some(value.trim_start()),
+        _ => None,
+    }
+}
+
+/// Reads keys out of a FlipperFormat file, front to back.
+pub struct Reader<S> {
+    source: S,
+}
+
+impl<'a> Reader<TextLines<&'a str>> {
+    pub fn new(text: &'a str) -> Self {
+        Self::from_source(TextLines::new(text))
+    }
+}
+
+impl<S: LineSource> Reader<S> {
+    pub fn from_source(source: S) -> Self {
+        Self { source }
+    }
+
+    /// Where the next read starts looking, for `seek`.
+    pub fn tell(&self) -> usize {
+        self.source.tell()
+    }
+
+    /// Go back (or forward) to a position from `tell`.
+    pub fn seek(&mut self, position: usize) {
+        self.source.seek(position);
+    }
+
+    /// The value of the next `key` line from here on, leaving the reader just
+    /// past it. A missing key leaves the reader at the end, like the firmware.
+    pub fn read_string(&mut self, key: &str) -> Option<&str> {
+        // Found first, then borrowed: returning the borrow from inside the
+        // loop would hold it across the next `advance`
+        loop {
+            if !self.source.advance() {
+                return None;
+            }
+            if line_value(self.source.line(), key).is_some() {
+                break;
+            }
+        }
+        line_value(self.source.line(), key)
+    }
+
+    /// The `Version` of a file that starts with the given `Filetype`, or
+    /// `None` for any other file.
+    pub fn read_header(&mut self, filetype: &str) -> Option<u32> {
+        if self.read_string(FILETYPE_KEY)? != filetype {
+            return None;
+        }
+        self.read_uint32(VERSION_KEY)
+    }
+
+    pub fn read_uint32(&mut self, key: &str) -> Option<u32> {
+        let mut value = [0];
+        self.read_uint32_array(key, &mut value).then_some(value[0])
+    }
+
+    /// Fill all of `values` from one `key` line, or report false.
+    pub fn read_uint32_array(&mut self, key: &str, values: &mut [u32]) -> bool {
+        self.read_array(key, values)
+    }
+
+    pub fn read_int32(&mut self, key: &str) -> Option<i32> {
+        let mut value = [0];
+        self.read_array(key, &mut value).then_some(value[0])
+    }
+
+    pub fn read_bool(&mut self, key: &str) -> Option<bool> {
+        match self.read_string(key)? {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Run `read` and rewind if it finds nothing, so a missing key doesn't
+    /// send the next read past keys that are there.
+    pub fn optional<T>(&mut self, read: impl FnOnce(&mut Self) -> Option<T>) -> Option<T> {
+        let position = self.tell();
+        let value = read(self);
+        if value.is_none() {
+            self.seek(position);
+        }
+        value
+    }
+
+    fn read_array<T: core::str::FromStr>(&mut self, key: &str, values: &mut [T]) -> bool {
+        let Some(line) = self.read_string(key) else {
+            return false;
+        };
+        let mut words = line.split_ascii_whitespace();
+        for value in values.iter_mut() {
+            match words.next().and_then(|word| word.parse().ok()) {
+                Some(parsed) => *value = parsed,
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Writes a FlipperFormat file as the firmware would lay it out.
+pub struct Writer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Hand back whatever was written to.
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    pub fn write_header(&mut self, filetype: &str, version: u32) -> fmt::Result {
+        self.write_string(FILETYPE_KEY, filetype)?;
+        self.write_uint32(VERSION_KEY, &[version])
+    }
+
+    pub fn write_comment(&mut self, text: &str) -> fmt::Result {
+        writeln!(self.out, "# {text}")
+    }
+
+    pub fn write_string(&mut self, key: &str, value: &str) -> fmt::Result {
+        writeln!(self.out, "{key}: {value}")
+    }
+
+    /// One `key` line holding every value, space separated.
+    pub fn write_uint32(&mut self, key: &str, values: &[u32]) -> fmt::Result {
+        self.write_array(key, values)
+    }
+
+    pub fn write_int32(&mut self, key: &str, values: &[i32]) -> fmt::Result {
+        self.write_array(key, values)
+    }
+
+    pub fn write_bool(&mut self, key: &str, value: bool) -> fmt::Result {
+        self.write_string(key, if value { "true" } else { "false" })
+    }
+
+    fn write_array<T: fmt::Display>(&mut self, key: &str, values: &[T]) -> fmt::Result {
+        write!(self.out, "{key}:")?;
+        for value in values {
+            write!(self.out, " {value}")?;
+        }
+        self.out.write_str("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::string::String;
+
+    use super::*;
+
+    const FILE: &str = "Filetype: Somfy RTS State\n\
+                        Version: 2\n\
+                        # Written by hand\n\
+                        Count: 2\n\
+                        Name: Living Room\n\
+                        Timings: 2416 4550 604 1208 1208 604 30415 27000\n\
+                        Name: Bedroom\r\n\
+                        Favourite: -1\n";
+
+    #[test]
+    fn test_reads_header_and_typed_values() {
+        let mut reader = Reader::new(FILE);
+        assert_eq!(reader.read_header("Somfy RTS State"), Some(2));
+        assert_eq!(reader.read_uint32("Count"), Some(2));
+        assert_eq!(reader.read_string("Name"), Some("Living Room"));
+
+        let mut timings = [0; 8];
+        assert!(reader.read_uint32_array("Timings", &mut timings));
+        assert_eq!(timings, [2416, 4550, 604, 1208, 1208, 604, 30415, 27000]);
+
+        assert_eq!(reader.read_string("Name"), Some("Bedroom"), "CRLF is trimmed");
+        assert_eq!(reader.read_int32("Favourite"), Some(-1));
+    }
+
+    #[test]
+    fn test_reads_search_forward_only() {
+        let mut reader = Reader::new(FILE);
+        assert_eq!(reader.read_string("Name"), Some("Living Room"));
+        assert_eq!(reader.read_uint32("Count"), None, "Count is behind us");
+        assert_eq!(reader.tell(), FILE.len(), "a miss leaves the reader at the end");
+        assert_eq!(reader.read_string("Name"), None);
+    }
+
+    #[test]
+    fn test_optional_rewinds_on_a_miss() {
+        let mut reader = Reader::new(FILE);
+        reader.read_string("Name");
+        assert_eq!(reader.optional(|r| r.read_uint32("Repeats")), None);
+        assert_eq!(reader.read_string("Name"), Some("Bedroom"));
+    }
+
+    #[test]
+    fn test_bad_values_and_comments() {
+        let mut reader = Reader::new("Count: many\n#Count: 3\nFlag: yes\nShort: 1 2\n");
+        assert_eq!(reader.read_uint32("Count"), None);
+        reader.seek(0);
+        assert_eq!(reader.read_bool("Flag"), None);
+        let mut values = [0; 3];
+        assert!(!reader.read_uint32_array("Short", &mut values), "too few values");
+        reader.seek(0);
+        assert_eq!(reader.read_string("#Count"), None, "comments are never keys");
+        reader.seek(0);
+        assert_eq!(reader.read_header("Somfy RTS State"), None, "not a header at all");
+        let mut reader = Reader::new(FILE);
+        assert_eq!(reader.read_header("Somfy RTS Settings"), None, "some other file");
+    }
+
+    #[test]
+    fn test_writer_output() {
+        let mut writer = Writer::new(String::new());
+        writer.write_header("Somfy RTS Settings", 1).unwrap();
+        writer.write_comment("Made on a Flipper").unwrap();
+        writer.write_string("Radio", "external").unwrap();
+        writer.write_bool("ListenBeforeTalk", true).unwrap();
+        writer.write_int32("LbtThreshold", &[-75]).unwrap();
+        writer.write_uint32("TravelTimes", &[21000, 19500]).unwrap();
+        assert_eq!(
+            writer.into_inner(),
+            "Filetype: Somfy RTS Settings\n\
+             Version: 1\n\
+             # Made on a Flipper\n\
+             Radio: external\n\
+             ListenBeforeTalk: true\n\
+             LbtThreshold: -75\n\
+             TravelTimes: 21000 19500\n"
+        );
+    }
+
+    #[test]
+    fn test_written_values_read_back() {
+        let mut writer = Writer::new(String::new());
+        writer.write_header("Test", 3).unwrap();
+        writer.write_string("Room", "").unwrap();
+        writer.write_bool("On", false).unwrap();
+        let text = writer.into_inner();
+
+        let mut reader = Reader::new(&text);
+        assert_eq!(reader.read_header("Test"), Some(3));
+        assert_eq!(reader.read_string("Room"), Some(""));
+        assert_eq!(reader.read_bool("On"), Some(false));
+    }
+}
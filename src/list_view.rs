@@ -0,0 +1,174 @@
+//! A scrolling list cursor — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! The screen fits a handful of rows; this keeps track of which one is picked
+//! and which slice of the list is showing around it, and writes that slice
+//! out as text. However long the list, a cat only ever sees what's in front
+//! of its nose :3
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+/// Rows that fit under the header on the Flipper's screen.
+pub const VISIBLE_ROWS: usize = 4;
+
+/// Which row is picked, and which rows are on screen around it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ListView {
+    /// Rows in the list.
+    pub len: usize,
+    /// The picked row, always below `len` unless the list is empty.
+    pub selected: usize,
+    /// The first row on screen.
+    pub top: usize,
+}
+
+impl ListView {
+    /// A view of `len` rows with `selected` picked and scrolled into sight.
+    pub fn new(len: usize, selected: usize) -> Self {
+        let mut view = Self { len, selected: selected.min(len.saturating_sub(1)), top: 0 };
+        view.scroll();
+        view
+    }
+
+    /// One row up, wrapping round to the bottom.
+    pub fn up(&mut self) {
+        if self.len > 0 {
+            self.selected = (self.selected + self.len - 1) % self.len;
+            self.scroll();
+        }
+    }
+
+    /// One row down, wrapping round to the top.
+    pub fn down(&mut self) {
+        if self.len > 0 {
+            self.selected = (self.selected + 1) % self.len;
+            self.scroll();
+        }
+    }
+
+    /// A screenful up, stopping at the first row.
+    pub fn page_up(&mut self) {
+        self.selected = self.selected.saturating_sub(VISIBLE_ROWS);
+        self.scroll();
+    }
+
+    /// A screenful down, stopping at the last row.
+    pub fn page_down(&mut self) {
+        self.selected = (self.selected + VISIBLE_ROWS).min(self.len.saturating_sub(1));
+        self.scroll();
+    }
+
+    /// The rows on screen.
+    pub fn visible(&self) -> Range<usize> {
+        self.top..(self.top + VISIBLE_ROWS).min(self.len)
+    }
+
+    /// Write the rows on screen, one per line, the picked one marked with `>`.
+    /// `row` writes the text of the row at an index.
+    pub fn write_rows<W: Write>(
+        &self,
+        out: &mut W,
+        mut row: impl FnMut(&mut W, usize) -> fmt::Result,
+    ) -> fmt::Result {
+        for index in self.visible() {
+            if index != self.top {
+                out.write_char('\n')?;
+            }
+            out.write_str(if index == self.selected { "> " } else { "  " })?;
+            row(out, index)?;
+        }
+        Ok(())
+    }
+
+    /// Move `top` just far enough to show the picked row, without leaving
+    /// empty rows at the bottom that earlier ones could fill.
+    fn scroll(&mut self) {
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + VISIBLE_ROWS {
+            self.top = self.selected + 1 - VISIBLE_ROWS;
+        }
+        self.top = self.top.min(self.len.saturating_sub(VISIBLE_ROWS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::string::String;
+
+    use super::*;
+
+    fn rows(view: &ListView) -> String {
+        let mut out = String::new();
+        view.write_rows(&mut out, |out, index| write!(out, "Blind {}", index + 1)).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_scrolls_to_keep_selection_visible() {
+        let mut view = ListView::new(10, 0);
+        assert_eq!(view.visible(), 0..4);
+        for _ in 0..4 {
+            view.down();
+        }
+        assert_eq!(view.selected, 4);
+        assert_eq!(view.visible(), 1..5);
+        view.up();
+        view.up();
+        view.up();
+        view.up();
+        assert_eq!(view.visible(), 0..4);
+    }
+
+    #[test]
+    fn test_wraps_round_one_row_at_a_time() {
+        let mut view = ListView::new(10, 0);
+        view.up();
+        assert_eq!(view.selected, 9);
+        assert_eq!(view.visible(), 6..10);
+        view.down();
+        assert_eq!(view.selected, 0);
+        assert_eq!(view.visible(), 0..4);
+    }
+
+    #[test]
+    fn test_pages_stop_at_the_ends() {
+        let mut view = ListView::new(10, 1);
+        view.page_down();
+        assert_eq!(view.selected, 5);
+        view.page_down();
+        view.page_down();
+        assert_eq!(view.selected, 9);
+        view.page_up();
+        assert_eq!(view.selected, 5);
+        view.page_up();
+        view.page_up();
+        assert_eq!(view.selected, 0);
+    }
+
+    #[test]
+    fn test_short_and_shrunk_lists() {
+        let view = ListView::new(2, 5);
+        assert_eq!(view.selected, 1);
+        assert_eq!(view.visible(), 0..2);
+
+        // After removing rows off the end, the screen fills up again
+        let view = ListView::new(6, 5);
+        assert_eq!(view.visible(), 2..6);
+
+        let mut empty = ListView::new(0, 3);
+        empty.down();
+        empty.page_down();
+        assert_eq!(empty.visible(), 0..0);
+        assert_eq!(rows(&empty), "");
+    }
+
+    #[test]
+    fn test_write_rows() {
+        let mut view = ListView::new(6, 0);
+        view.page_down();
+        assert_eq!(rows(&view), "  Blind 2\n  Blind 3\n  Blind 4\n> Blind 5");
+    }
+}
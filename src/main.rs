@@ -1,21 +1,43 @@
 #![no_main]
 #![no_std]
 
+extern crate alloc;
 extern crate flipperzero_alloc;
 extern crate flipperzero_rt;
 
+mod blind;
+mod flipper_format;
+mod list_view;
 mod protocol;
+mod pulse_ring;
+mod radio;
+mod screen;
+mod state_file;
 mod storage;
+mod sub_file;
 mod subghz;
+mod tx_log;
 
+use alloc::ffi::CString;
+use alloc::string::String;
 use core::ffi::CStr;
-use flipperzero::dialogs::{DialogMessage, DialogMessageButton, DialogsApp};
+use core::fmt::Write;
+use flipperzero::dialogs::{
+    DialogFileBrowserOptions, DialogMessage, DialogMessageButton, DialogsApp,
+};
+use flipperzero::furi::string::FuriString;
+use flipperzero::furi::time::FuriInstant;
 use flipperzero::gui::canvas::Align;
 use flipperzero::notification::{NotificationApp, led};
 use flipperzero_rt::{entry, manifest};
 
-use protocol::SomfyCommand;
-use storage::{SomfyBlind, SomfyState, MAX_BLINDS, MAX_NAME_LEN};
+use protocol::{FrameLength, Press, SomfyCommand, SomfyFrame, SomfyTimings, TransmissionEncoder};
+use blind::{SomfyBlind, MAX_NAME_LEN};
+use list_view::ListView;
+use radio::{Preset, RadioBackend, RadioDevice, SOMFY_FREQUENCY_HZ, TxError};
+use screen::{Key, KeyEvent, Screen};
+use storage::{AddError, ImportError, LoadError, Settings, SomfyState};
+use subghz::Cc1101;
 
 manifest!(
     name = "Somfy Blinds Rust",
@@ -28,53 +50,79 @@ entry!(main);
 fn main(_args: Option<&CStr>) -> i32 {
     let mut notif = NotificationApp::open();
     let mut dialogs = DialogsApp::open();
+    let mut settings = storage::load_settings();
+    let mut radio = Cc1101::new(settings.radio);
+    radio.set_listen_before_talk(settings.listen_before_talk);
 
     flipperzero::info!("Somfy Blinds Rust starting up, meow~");
 
     // Load persisted state
-    let mut state = storage::load_state();
+    let mut state = match storage::load_state() {
+        Ok(state) => state,
+        Err(error) => match recovery_dialog(&mut dialogs, error) {
+            Some(state) => state,
+            None => {
+                flipperzero::info!("Leaving the state file be, bye~");
+                return 0;
+            }
+        },
+    };
+    state.format = settings.state_format;
+    state.blind_memory = settings.blind_memory as usize;
     let mut selected: usize = 0;
 
     notif.notify(&led::ONLY_GREEN);
 
+    if settings.radio == RadioDevice::External && !subghz::probe_external() {
+        show_notice(&mut dialogs, c"Radio", c"External CC1101 missing\nUsing internal radio");
+    }
+
     loop {
         if state.blinds.is_empty() {
             // No blinds — offer to add one
             match show_empty_menu(&mut dialogs) {
-                Action::AddBlind => {
-                    add_blind(&mut state);
-                    let _ = storage::save_state(&state);
-                }
+                Action::AddBlind => new_blind(&mut dialogs, &mut state),
+                Action::Import => import_capture(&mut dialogs, &mut state),
+                Action::Listen => listen_for_remote(&mut dialogs, &mut radio, &mut state),
                 Action::Exit => break,
                 _ => {}
             }
         } else {
-            // Show blind selection
-            if selected >= state.blinds.len() {
-                selected = 0;
-            }
-            match show_blind_select(&mut dialogs, &state, selected) {
-                Action::PrevBlind => {
-                    if selected == 0 {
-                        selected = state.blinds.len() - 1;
-                    } else {
-                        selected -= 1;
-                    }
-                }
-                Action::NextBlind => {
-                    selected = (selected + 1) % state.blinds.len();
-                }
+            match blind_list(&state, &mut selected) {
+                Action::AddBlind => match show_add_options(&mut dialogs) {
+                    Action::AddBlind => new_blind(&mut dialogs, &mut state),
+                    Action::Import => import_capture(&mut dialogs, &mut state),
+                    Action::Listen => listen_for_remote(&mut dialogs, &mut radio, &mut state),
+                    _ => {}
+                },
                 Action::SelectBlind => {
                     // Enter control mode for this blind
-                    control_loop(&mut dialogs, &mut notif, &mut state, selected);
+                    control_loop(&mut dialogs, &mut notif, &mut radio, &mut state, selected);
                     let _ = storage::save_state(&state);
                 }
-                Action::Exit => break,
+                Action::Back => match show_app_menu(&mut dialogs, &settings) {
+                    Action::Radio => {
+                        toggle_radio(&mut dialogs, &mut settings);
+                        radio.set_preferred(settings.radio);
+                    }
+                    Action::ListenBeforeTalk => {
+                        toggle_listen_before_talk(&mut settings);
+                        radio.set_listen_before_talk(settings.listen_before_talk);
+                    }
+                    Action::Exit => break,
+                    _ => {}
+                },
                 _ => {}
             }
         }
     }
 
+    // Nothing is on air any more, so unsent codes from the last block can go back
+    for blind in state.blinds.iter_mut() {
+        blind.release_reservation();
+    }
+    let _ = storage::save_state(&state);
+
     flipperzero::info!("Bye bye, nyaa~ :3");
     notif.notify_blocking(&led::RESET_RGB);
     0
@@ -83,14 +131,19 @@ fn main(_args: Option<&CStr>) -> i32 {
 /// Actions that dialogs can produce.
 enum Action {
     Exit,
-    PrevBlind,
-    NextBlind,
     SelectBlind,
     AddBlind,
-    Up,
-    Down,
-    Stop,
-    Pair,
+    Import,
+    Listen,
+    Radio,
+    ListenBeforeTalk,
+    Commands,
+    BlindOptions,
+    Export,
+    ExportKey,
+    PrevCommand,
+    NextCommand,
+    Send,
     Remove,
     #[allow(dead_code)]
     More,
@@ -101,197 +154,810 @@ enum Action {
 fn show_empty_menu(dialogs: &mut DialogsApp) -> Action {
     let mut msg = DialogMessage::new();
     msg.set_header(c"Somfy Blinds", 0, 0, Align::Left, Align::Top);
-    msg.set_text(c"No blinds yet!\nPress OK to add one", 0, 26, Align::Left, Align::Top);
-    msg.set_buttons(None, Some(c"Add"), None);
+    msg.set_text(c"No blinds yet! Add one,\nimport one or listen", 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"Import"), Some(c"Add"), Some(c"Listen"));
 
     match dialogs.show_message(&msg) {
+        DialogMessageButton::Left => Action::Import,
         DialogMessageButton::Center => Action::AddBlind,
+        DialogMessageButton::Right => Action::Listen,
         DialogMessageButton::Back => Action::Exit,
-        _ => Action::Exit,
     }
 }
 
-/// Show blind selection dialog: Prev / [name] / Next.
-fn show_blind_select(dialogs: &mut DialogsApp, state: &SomfyState, selected: usize) -> Action {
-    let blind = &state.blinds[selected];
+/// The list of blinds, with an "Add blind" row at the end while there's
+/// room for one. Up/Down move, Left/Right page, OK picks and Back opens the
+/// app menu; `selected` follows the cursor.
+fn blind_list(state: &SomfyState, selected: &mut usize) -> Action {
+    let add_row = usize::from(!state.is_full());
+    let mut view = ListView::new(state.blinds.len() + add_row, *selected);
+    let mut header = String::new();
+    let _ = write!(header, "Somfy Blinds ({})", state.blinds.len());
+    let screen = Screen::open(&header, &blind_list_text(state, &view));
+
+    loop {
+        let event = screen.wait_event();
+        if !event.pressed {
+            continue;
+        }
+        match event.key {
+            Key::Up => view.up(),
+            Key::Down => view.down(),
+            Key::Left => view.page_up(),
+            Key::Right => view.page_down(),
+            Key::Ok if view.selected < state.blinds.len() => {
+                *selected = view.selected;
+                return Action::SelectBlind;
+            }
+            Key::Ok => return Action::AddBlind,
+            Key::Back => {
+                *selected = view.selected;
+                return Action::Back;
+            }
+        }
+        screen.set_text(&blind_list_text(state, &view));
+    }
+}
+
+/// The rows of `blind_list` that are on screen.
+fn blind_list_text(state: &SomfyState, view: &ListView) -> String {
+    let mut text = String::new();
+    let _ = view.write_rows(&mut text, |out, index| match state.blinds.get(index) {
+        Some(blind) => out.write_str(&blind.name),
+        None => out.write_str("+ Add blind"),
+    });
+    text
+}
 
-    // Build display text with blind name and index
-    let mut text_buf = [0u8; 40];
-    let _text_len = fmt_blind_info(&mut text_buf, blind, selected, state.blinds.len());
-    let text_cstr = unsafe { CStr::from_ptr(text_buf.as_ptr() as *const _) };
+/// Show app-wide options: Radio / Exit / LBT.
+fn show_app_menu(dialogs: &mut DialogsApp, settings: &Settings) -> Action {
+    let radio = match settings.radio {
+        RadioDevice::Internal => "internal",
+        RadioDevice::External if subghz::probe_external() => "external",
+        RadioDevice::External => "ext (missing!)",
+    };
+    let lbt = settings.listen_before_talk;
+    let mut text = FuriString::new();
+    let _ = write!(text, "Radio: {radio}\nListen first: ");
+    if lbt.enabled {
+        let _ = write!(text, "{} dBm", lbt.threshold_dbm);
+    } else {
+        let _ = write!(text, "off");
+    }
 
     let mut msg = DialogMessage::new();
     msg.set_header(c"Somfy Blinds", 0, 0, Align::Left, Align::Top);
-    msg.set_text(text_cstr, 0, 26, Align::Left, Align::Top);
+    msg.set_text(text.as_c_str(), 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"Radio"), Some(c"Exit"), Some(c"LBT"));
+
+    match dialogs.show_message(&msg) {
+        DialogMessageButton::Left => Action::Radio,
+        DialogMessageButton::Center => Action::Exit,
+        DialogMessageButton::Right => Action::ListenBeforeTalk,
+        _ => Action::Back,
+    }
+}
+
+/// Switch between the internal and external radio and persist the choice.
+fn toggle_radio(dialogs: &mut DialogsApp, settings: &mut Settings) {
+    settings.radio = match settings.radio {
+        RadioDevice::Internal => RadioDevice::External,
+        RadioDevice::External => RadioDevice::Internal,
+    };
+    let _ = storage::save_settings(settings);
+    flipperzero::info!("Radio set to {}", settings.radio.encode());
+
+    if settings.radio == RadioDevice::External && !subghz::probe_external() {
+        show_notice(dialogs, c"Radio", c"External CC1101 missing\nUsing internal for now");
+    }
+}
+
+/// Turn listen-before-talk on or off and persist the choice.
+fn toggle_listen_before_talk(settings: &mut Settings) {
+    let enabled = !settings.listen_before_talk.enabled;
+    settings.listen_before_talk.enabled = enabled;
+    let _ = storage::save_settings(settings);
+    flipperzero::info!("Listen before talk {}", if enabled { "on" } else { "off" });
+}
+
+/// What a key does on the control screen.
+fn key_command(key: Key) -> Option<SomfyCommand> {
+    match key {
+        Key::Up => Some(SomfyCommand::Up),
+        Key::Ok => Some(SomfyCommand::Stop),
+        Key::Down => Some(SomfyCommand::Down),
+        _ => None,
+    }
+}
 
-    if state.blinds.len() > 1 {
-        msg.set_buttons(Some(c"<"), Some(c"OK"), Some(c">"));
+/// Text under the blind's name on the control screen.
+fn control_text<R: RadioBackend>(radio: &R) -> &'static str {
+    if radio.fell_back() {
+        "Hold Up / OK / Down\nExternal radio missing!"
     } else {
-        msg.set_buttons(None, Some(c"OK"), Some(c"+"));
+        "Hold Up / OK / Down\nBack: more options"
     }
+}
 
-    match dialogs.show_message(&msg) {
-        DialogMessageButton::Left => {
-            if state.blinds.len() > 1 {
-                Action::PrevBlind
-            } else {
-                Action::Exit
-            }
+/// The control screen: Up, OK (Stop) and Down transmit for as long as
+/// they're held, like the buttons on a real remote. Returns on Back.
+fn remote_screen<R: RadioBackend>(
+    notif: &mut NotificationApp,
+    radio: &mut R,
+    state: &mut SomfyState,
+    selected: usize,
+) {
+    let screen = Screen::open(&state.blinds[selected].name, control_text(radio));
+    loop {
+        let event = screen.wait_event();
+        if !event.pressed {
+            continue;
         }
-        DialogMessageButton::Center => Action::SelectBlind,
-        DialogMessageButton::Right => {
-            if state.blinds.len() > 1 {
-                Action::NextBlind
-            } else {
-                // "+" button — add a blind
-                Action::AddBlind
-            }
+        if event.key == Key::Back {
+            return;
+        }
+        if let Some(command) = key_command(event.key) {
+            screen.set_text("Transmitting...\nRelease to stop");
+            let release = KeyEvent { key: event.key, pressed: false };
+            do_transmit(notif, radio, state, selected, command, HELD_PRESS, &screen, release);
+            screen.set_text(control_text(radio));
         }
-        DialogMessageButton::Back => Action::Exit,
     }
 }
 
 /// Control loop for a selected blind.
-fn control_loop(
+fn control_loop<R: RadioBackend>(
     dialogs: &mut DialogsApp,
     notif: &mut NotificationApp,
+    radio: &mut R,
     state: &mut SomfyState,
     selected: usize,
 ) {
     loop {
-        let blind = &state.blinds[selected];
-        let mut name_buf = [0u8; MAX_NAME_LEN + 1];
-        let name_bytes = blind.name.as_bytes();
-        let len = name_bytes.len().min(MAX_NAME_LEN);
-        name_buf[..len].copy_from_slice(&name_bytes[..len]);
-        let name_cstr = unsafe { CStr::from_ptr(name_buf.as_ptr() as *const _) };
-
-        let mut msg = DialogMessage::new();
-        msg.set_header(name_cstr, 0, 0, Align::Left, Align::Top);
-        msg.set_text(c"Control blind", 0, 26, Align::Left, Align::Top);
-        msg.set_buttons(Some(c"Up"), Some(c"Stop"), Some(c"Down"));
-
-        let action = match dialogs.show_message(&msg) {
-            DialogMessageButton::Left => Action::Up,
-            DialogMessageButton::Center => Action::Stop,
-            DialogMessageButton::Right => Action::Down,
-            DialogMessageButton::Back => Action::Back,
-        };
-
-        match action {
-            Action::Up => do_transmit(notif, state, selected, SomfyCommand::Up),
-            Action::Stop => do_transmit(notif, state, selected, SomfyCommand::Stop),
-            Action::Down => do_transmit(notif, state, selected, SomfyCommand::Down),
-            Action::Back => {
-                // Show more options or go back
-                match show_more_options(dialogs) {
-                    Action::Pair => do_transmit(notif, state, selected, SomfyCommand::Prog),
-                    Action::Remove => {
-                        remove_blind(state, selected);
-                        return;
-                    }
-                    Action::AddBlind => {
-                        add_blind(state);
-                        return;
-                    }
-                    _ => return,
+        remote_screen(notif, radio, state, selected);
+
+        // Show more options or go back
+        match show_more_options(dialogs) {
+            Action::Commands => {
+                command_menu(dialogs, notif, radio, state, selected, MenuMode::Send);
+            }
+            Action::AddBlind => {
+                match show_add_options(dialogs) {
+                    Action::AddBlind => new_blind(dialogs, state),
+                    Action::Import => import_capture(dialogs, state),
+                    Action::Listen => listen_for_remote(dialogs, radio, state),
+                    _ => {}
                 }
+                return;
             }
-            _ => {}
+            Action::BlindOptions => match show_blind_options(dialogs) {
+                Action::Export => {
+                    command_menu(dialogs, notif, radio, state, selected, MenuMode::Export);
+                }
+                Action::ExportKey => {
+                    command_menu(dialogs, notif, radio, state, selected, MenuMode::ExportKey);
+                }
+                Action::Remove => {
+                    remove_blind(state, selected);
+                    return;
+                }
+                _ => {}
+            },
+            _ => return,
         }
     }
 }
 
-/// Show additional options: Pair / Remove / Back.
+/// Show additional options: Commands / Add / More.
 fn show_more_options(dialogs: &mut DialogsApp) -> Action {
     let mut msg = DialogMessage::new();
     msg.set_header(c"More Options", 0, 0, Align::Left, Align::Top);
-    msg.set_text(c"Pair, add or remove?", 0, 26, Align::Left, Align::Top);
-    msg.set_buttons(Some(c"Pair"), Some(c"+Add"), Some(c"Rm"));
+    msg.set_text(c"Commands, add a blind\nor more for this one?", 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"Cmds"), Some(c"+Add"), Some(c"More"));
 
     match dialogs.show_message(&msg) {
-        DialogMessageButton::Left => Action::Pair,
+        DialogMessageButton::Left => Action::Commands,
         DialogMessageButton::Center => Action::AddBlind,
+        DialogMessageButton::Right => Action::BlindOptions,
+        DialogMessageButton::Back => Action::Back,
+    }
+}
+
+/// Show how to add a blind: a new remote, or one taking over from a remote
+/// heard on air or imported from a capture.
+fn show_add_options(dialogs: &mut DialogsApp) -> Action {
+    let mut msg = DialogMessage::new();
+    msg.set_header(c"Add Blind", 0, 0, Align::Left, Align::Top);
+    msg.set_text(c"New remote, listen to\none or import a .sub?", 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"New"), Some(c"Listen"), Some(c"Import"));
+
+    match dialogs.show_message(&msg) {
+        DialogMessageButton::Left => Action::AddBlind,
+        DialogMessageButton::Center => Action::Listen,
+        DialogMessageButton::Right => Action::Import,
+        _ => Action::Back,
+    }
+}
+
+/// Show options for the selected blind: RAW export / Key export / Remove.
+fn show_blind_options(dialogs: &mut DialogsApp) -> Action {
+    let mut msg = DialogMessage::new();
+    msg.set_header(c"Blind Options", 0, 0, Align::Left, Align::Top);
+    msg.set_text(c"Export as RAW or Somfy\nkey, or remove blind?", 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"RAW"), Some(c"Key"), Some(c"Rm"));
+
+    match dialogs.show_message(&msg) {
+        DialogMessageButton::Left => Action::Export,
+        DialogMessageButton::Center => Action::ExportKey,
         DialogMessageButton::Right => Action::Remove,
+        _ => Action::Back,
+    }
+}
+
+/// A normal button press — the same 4 frames the C app sends.
+const SHORT_PRESS: Press = Press::Frames(4);
+
+/// A button on the control screen: repeats until released, like a genuine
+/// remote, with a short press's frames for a quick tap.
+const HELD_PRESS: Press = Press::Held(4);
+
+/// An entry in the command menu: a command and how long to press it.
+struct MenuEntry {
+    label: &'static CStr,
+    command: SomfyCommand,
+    press: Press,
+}
+
+/// Commands offered in the command menu — the whole command table, including
+/// Prog for pairing and the long presses used to program motors.
+const MENU_ENTRIES: [MenuEntry; 13] = [
+    MenuEntry {
+        label: c"Up",
+        command: SomfyCommand::Up,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Stop",
+        command: SomfyCommand::Stop,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Down",
+        command: SomfyCommand::Down,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Prog (pair)",
+        command: SomfyCommand::Prog,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Hold Prog 3s\n(add/remove remote)",
+        command: SomfyCommand::Prog,
+        press: Press::HoldMs(3000),
+    },
+    MenuEntry {
+        label: c"My (favourite)",
+        command: SomfyCommand::My,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Hold My 5s\n(store favourite)",
+        command: SomfyCommand::My,
+        press: Press::HoldMs(5000),
+    },
+    MenuEntry {
+        label: c"My + Up",
+        command: SomfyCommand::MyUp,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"My + Down",
+        command: SomfyCommand::MyDown,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Up + Down",
+        command: SomfyCommand::UpDown,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"My + Up + Down",
+        command: SomfyCommand::MyUpDown,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Sun + Flag (sun on)",
+        command: SomfyCommand::SunFlag,
+        press: SHORT_PRESS,
+    },
+    MenuEntry {
+        label: c"Flag (sun off)",
+        command: SomfyCommand::Flag,
+        press: SHORT_PRESS,
+    },
+];
+
+/// What OK does in the command menu.
+#[derive(Clone, Copy, PartialEq)]
+enum MenuMode {
+    /// Transmit the command.
+    Send,
+    /// Save the command as a RAW .sub file instead of transmitting it.
+    Export,
+    /// Save the command as a stock `Somfy Telis` key file.
+    ExportKey,
+}
+
+/// Command menu for a selected blind: cycle through commands with < / > and
+/// send or export with OK.
+fn command_menu<R: RadioBackend>(
+    dialogs: &mut DialogsApp,
+    notif: &mut NotificationApp,
+    radio: &mut R,
+    state: &mut SomfyState,
+    selected: usize,
+    mode: MenuMode,
+) {
+    let mut index = 0;
+    loop {
+        let entry = &MENU_ENTRIES[index];
+        match show_command_select(dialogs, entry, mode) {
+            Action::PrevCommand => {
+                index = (index + MENU_ENTRIES.len() - 1) % MENU_ENTRIES.len();
+            }
+            Action::NextCommand => index = (index + 1) % MENU_ENTRIES.len(),
+            Action::Send => match mode {
+                MenuMode::Send => {
+                    let name = &state.blinds[selected].name;
+                    let screen = Screen::open(name, "Transmitting...\nBack to cancel");
+                    let command = entry.command;
+                    do_transmit(notif, radio, state, selected, command, entry.press, &screen, BACK);
+                }
+                MenuMode::Export => {
+                    do_export(dialogs, state, selected, entry.command, entry.press);
+                    return;
+                }
+                MenuMode::ExportKey => {
+                    do_export_key(dialogs, state, selected, entry.command);
+                    return;
+                }
+            },
+            _ => return,
+        }
+    }
+}
+
+/// Show command selection dialog: < / Send / > (or < / Save / > when exporting).
+fn show_command_select(dialogs: &mut DialogsApp, entry: &MenuEntry, mode: MenuMode) -> Action {
+    let (header, ok) = match mode {
+        MenuMode::Send => (c"Commands", c"Send"),
+        MenuMode::Export => (c"Export .sub", c"Save"),
+        MenuMode::ExportKey => (c"Export key", c"Save"),
+    };
+
+    let mut msg = DialogMessage::new();
+    msg.set_header(header, 0, 0, Align::Left, Align::Top);
+    msg.set_text(entry.label, 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"<"), Some(ok), Some(c">"));
+
+    match dialogs.show_message(&msg) {
+        DialogMessageButton::Left => Action::PrevCommand,
+        DialogMessageButton::Center => Action::Send,
+        DialogMessageButton::Right => Action::NextCommand,
         DialogMessageButton::Back => Action::Back,
     }
 }
 
+/// How often the transmitting screen checks back on the radio.
+const TX_POLL_MS: u32 = 20;
+
+/// How long a TX error stays on screen, unless a key is pressed first.
+const TX_ERROR_SHOW_MS: u32 = 2000;
+
+/// Back going down — what cancels a timed press.
+const BACK: KeyEvent = KeyEvent { key: Key::Back, pressed: true };
+
 /// Transmit a command and update rolling code.
-fn do_transmit(
+///
+/// The radio streams in the background while `screen` stays up; `stop` ends
+/// the press once the frame on air is finished — Back to cancel a timed
+/// press, or the key's release for a held one.
+#[allow(clippy::too_many_arguments)]
+fn do_transmit<R: RadioBackend>(
     notif: &mut NotificationApp,
+    radio: &mut R,
     state: &mut SomfyState,
     selected: usize,
     command: SomfyCommand,
+    press: Press,
+    screen: &Screen,
+    stop: KeyEvent,
 ) {
+    let press = state.blinds[selected].press(press);
+    let frame = state.blinds[selected].frame(command);
+    flipperzero::info!(
+        "TX: cmd={} addr={} rc={}",
+        frame.command().nibble(),
+        frame.address().get(),
+        frame.rolling_code()
+    );
+
+    // Write-ahead: once the code is on air it must already be used up on the card
+    let result = if storage::reserve_rolling_code(state, selected) {
+        let started = FuriInstant::now();
+        let now_ms = || started.elapsed().as_millis() as u32;
+
+        let mut result = None;
+        let blind = &mut state.blinds[selected];
+        let on_complete = |r| result = Some(r);
+        let mut tx = radio::start_send(radio, blind, command, press, now_ms(), on_complete);
+        while !tx.poll(now_ms()) {
+            if screen.next_event(TX_POLL_MS) == Some(stop) {
+                tx.cancel();
+            }
+        }
+        drop(tx);
+        // `on_complete` has always run once the transmission is dropped
+        result.unwrap_or(Err(TxError::Cancelled))
+    } else {
+        Err(TxError::ReserveFailed)
+    };
+
     let blind = &state.blinds[selected];
-    flipperzero::info!("TX: addr={} rc={}", blind.address, blind.rolling_code);
+    let _ = storage::log_transmission(frame, blind.frequency, radio.device(), result);
 
-    let success = subghz::transmit(command, blind.rolling_code, blind.address, 4);
+    match result {
+        Ok(()) => {
+            notif.notify(&led::ONLY_GREEN);
+            flipperzero::info!("TX success, new rc={}", blind.rolling_code);
+        }
+        Err(TxError::Cancelled | TxError::CancelledWaiting) => {
+            notif.notify(&led::ONLY_BLUE);
+            flipperzero::info!("TX cancelled, new rc={}", blind.rolling_code);
+        }
+        Err(error) => {
+            notif.notify(&led::ONLY_RED);
+            flipperzero::error!("TX failed: {}", error.code());
+
+            let mut text = String::from("TX failed:\n");
+            text.push_str(error.message());
+            screen.set_text(&text);
+            screen.next_event(TX_ERROR_SHOW_MS);
+        }
+    }
+}
 
+/// Save a command as a RAW .sub file for the stock Sub-GHz app.
+///
+/// The file carries the blind's current rolling code, so the code is used up
+/// just like a transmission — otherwise replaying the file would burn a code
+/// the app is about to send itself.
+fn do_export(
+    dialogs: &mut DialogsApp,
+    state: &mut SomfyState,
+    selected: usize,
+    command: SomfyCommand,
+    press: Press,
+) {
+    // The file carries the code, so it's reserved like a transmission
+    if !storage::reserve_rolling_code(state, selected) {
+        show_notice(dialogs, c"Export .sub", c"Could not save rolling code");
+        return;
+    }
+    let blind = &state.blinds[selected];
+    let frame = blind.frame(command);
+    let frames = blind.press(press).frame_count(frame.length(), &blind.timings);
+    let timings = TransmissionEncoder::new(&frame, &blind.timings, Some(frames));
+
+    // Long holds run to tens of KB, so the pulses go straight to the file
+    let path = sub_file::export_path(frame.address(), frame.rolling_code());
+    let success = storage::export_file(&path, |mut out| {
+        sub_file::write_raw(&mut out, blind.frequency, blind.preset, timings)
+    });
     if success {
-        notif.notify(&led::ONLY_GREEN);
-        // Increment rolling code
-        let blind = &mut state.blinds[selected];
-        blind.rolling_code = blind.rolling_code.wrapping_add(1);
-        if blind.rolling_code == 0 {
-            blind.rolling_code = 1;
-        }
-        let _ = storage::save_state(state);
-        flipperzero::info!("TX success, new rc={}", state.blinds[selected].rolling_code);
+        state.blinds[selected].advance_rolling_code();
+        flipperzero::info!("Exported to {}", path.as_str());
+        show_notice_text(dialogs, c"Export .sub", &path);
     } else {
-        notif.notify(&led::ONLY_RED);
-        flipperzero::error!("TX failed!");
+        flipperzero::error!("Export failed!");
+        show_notice(dialogs, c"Export .sub", c"Could not write file");
     }
 }
 
-/// Add a new blind with auto-generated name and address.
-fn add_blind(state: &mut SomfyState) {
-    if state.blinds.len() >= MAX_BLINDS {
+/// Save a command as a `Somfy Telis` key for the stock Sub-GHz app.
+///
+/// Like a RAW export, this uses up the blind's current rolling code.
+fn do_export_key(
+    dialogs: &mut DialogsApp,
+    state: &mut SomfyState,
+    selected: usize,
+    command: SomfyCommand,
+) {
+    let blind = &state.blinds[selected];
+    if blind.frame_length != FrameLength::Standard {
+        show_notice(dialogs, c"Export key", c"Somfy keys are 56-bit only");
+        return;
+    }
+    if !storage::reserve_rolling_code(state, selected) {
+        show_notice(dialogs, c"Export key", c"Could not save rolling code");
         return;
     }
 
-    let index = state.blinds.len();
-    let mut name = heapless::String::<MAX_NAME_LEN>::new();
-    let _ = name.push_str("Blind ");
-    // Simple number formatting without alloc
-    let num = index + 1;
-    if num >= 10 {
-        let _ = name.push(char::from(b'0' + (num / 10) as u8));
+    let Some(path) = storage::export_telis_key(&state.blinds[selected], command) else {
+        flipperzero::error!("Key export failed!");
+        show_notice(dialogs, c"Export key", c"Could not write file");
+        return;
+    };
+    state.blinds[selected].advance_rolling_code();
+    flipperzero::info!("Exported key to {}", path.as_str());
+    show_notice_text(dialogs, c"Export key", &path);
+}
+
+/// Largest `.sub` file we'll load — several seconds of RAW recording.
+const MAX_CAPTURE_BYTES: usize = 32 * 1024;
+
+/// Pick a `.sub` file and import it.
+///
+/// RAW captures list the Somfy frames in them, with the option to add a blind
+/// that continues the captured remote; `Somfy Telis` keys become a blind
+/// straight away.
+fn import_capture(dialogs: &mut DialogsApp, state: &mut SomfyState) {
+    let mut start = FuriString::from(sub_file::EXPORT_DIR);
+    // SAFETY: the extension is valid UTF-8
+    let options = unsafe { DialogFileBrowserOptions::with_extension(c".sub") };
+    let Some(path) = dialogs.show_file_browser(Some(&mut start), Some(&options)) else {
+        return;
+    };
+
+    let Some(bytes) = storage::read_file(path.as_c_str(), MAX_CAPTURE_BYTES) else {
+        show_notice(dialogs, c"Import .sub", c"Could not read file");
+        return;
+    };
+    let capture = match core::str::from_utf8(&bytes).map(sub_file::parse_raw) {
+        Ok(Ok(capture)) => capture,
+        Ok(Err(sub_file::ParseError::NotRaw)) => {
+            drop(bytes);
+            import_key(dialogs, state, path.as_c_str());
+            return;
+        }
+        _ => {
+            show_notice(dialogs, c"Import .sub", c"Malformed RAW data");
+            return;
+        }
+    };
+    drop(bytes);
+
+    let frames = protocol::decode_all(&capture.timings, &SomfyTimings::DEFAULT);
+    if frames.is_empty() {
+        show_notice(dialogs, c"Import .sub", c"No Somfy frames found");
+        return;
     }
-    let _ = name.push(char::from(b'0' + (num % 10) as u8));
+    flipperzero::info!("Decoded {} frames from capture", frames.len());
+
+    pick_frame(dialogs, state, &frames, capture.frequency, capture.preset, c"Import .sub");
+}
+
+/// How long to wait for a remote's button to be pressed.
+const LISTEN_MS: u32 = 10_000;
 
-    let blind = SomfyBlind {
-        name,
-        address: 0x100001 + (index as u32) + 1,
-        rolling_code: 1,
+/// Listen for a Somfy remote on the usual frequency, with the option to add a
+/// blind that continues it.
+fn listen_for_remote<R: RadioBackend>(
+    dialogs: &mut DialogsApp,
+    radio: &mut R,
+    state: &mut SomfyState,
+) {
+    let screen = Screen::open("Listening", "Press a button on\nthe remote to copy");
+    let heard = radio::listen(radio, SOMFY_FREQUENCY_HZ, Preset::Ook650Async, LISTEN_MS);
+    drop(screen);
+
+    let frames = match heard {
+        Ok(frames) if !frames.is_empty() => frames,
+        Ok(_) => {
+            show_notice(dialogs, c"Listen", c"No Somfy remote heard");
+            return;
+        }
+        Err(error) => {
+            show_notice_text(dialogs, c"Listen", error.message());
+            return;
+        }
     };
-    let _ = state.blinds.push(blind);
-    flipperzero::info!("Added blind {} at address {}", index + 1, 0x100001 + (index as u32) + 1);
+    flipperzero::info!("Heard {} frames", frames.len());
+
+    let (frequency, preset) = (Some(SOMFY_FREQUENCY_HZ), Some(Preset::Ook650Async));
+    pick_frame(dialogs, state, &frames, frequency, preset, c"Listen");
 }
 
-/// Remove a blind by index, shifting others down.
-fn remove_blind(state: &mut SomfyState, index: usize) {
-    if index < state.blinds.len() {
-        state.blinds.remove(index);
-        flipperzero::info!("Removed blind {}", index);
+/// Page through decoded `frames` and add a blind continuing the one picked,
+/// sending on `frequency` with `preset` as its remote did, where known.
+fn pick_frame(
+    dialogs: &mut DialogsApp,
+    state: &mut SomfyState,
+    frames: &[SomfyFrame],
+    frequency: Option<u32>,
+    preset: Option<Preset>,
+    header: &CStr,
+) {
+    let mut index = 0;
+    loop {
+        match show_frame_select(dialogs, &frames[index], index, frames.len()) {
+            Action::PrevCommand => index = (index + frames.len() - 1) % frames.len(),
+            Action::NextCommand => index = (index + 1) % frames.len(),
+            Action::AddBlind => {
+                match import_blind(state, frames[index], frequency, preset) {
+                    Ok(()) => {
+                        let _ = storage::save_state(state);
+                    }
+                    Err(AddError::Full) => show_notice(dialogs, header, c"No room for more blinds"),
+                    Err(AddError::DuplicateAddress) => {
+                        show_notice(dialogs, header, c"That remote is already\na blind here")
+                    }
+                }
+                return;
+            }
+            _ => return,
+        }
     }
 }
 
-/// Format blind info into a buffer: "Name\n(1/3) addr:0x100002"
-fn fmt_blind_info(buf: &mut [u8; 40], blind: &SomfyBlind, _index: usize, _total: usize) -> usize {
-    let mut pos = 0;
+/// Import a `Somfy Telis` key file picked in `import_capture`.
+fn import_key(dialogs: &mut DialogsApp, state: &mut SomfyState, path: &CStr) {
+    let text = match storage::import_telis_key(state, path) {
+        Ok(()) => {
+            let _ = storage::save_state(state);
+            c"Blind added"
+        }
+        Err(ImportError::Parse(sub_file::ParseError::NotSomfyTelis)) => {
+            c"Not a RAW or Somfy file"
+        }
+        Err(ImportError::Parse(_)) => c"Malformed Somfy key",
+        Err(ImportError::Full) => c"No room for more blinds",
+        Err(ImportError::DuplicateAddress) => c"That remote is already\na blind here",
+        Err(ImportError::Read) => c"Could not read file",
+    };
+    show_notice(dialogs, c"Import .sub", text);
+}
+
+/// Show one decoded frame: < / Add / >.
+fn show_frame_select(
+    dialogs: &mut DialogsApp,
+    frame: &SomfyFrame,
+    index: usize,
+    total: usize,
+) -> Action {
+    let mut text = heapless::String::<64>::new();
+    let _ = write!(
+        text,
+        "({}/{}) {:06X}\n{:?} rc {:04X} key {:02X}\0",
+        index + 1,
+        total,
+        frame.address().get(),
+        frame.command(),
+        frame.rolling_code(),
+        frame.key(),
+    );
+    let text_cstr = CStr::from_bytes_until_nul(text.as_bytes()).unwrap_or(c"");
+
+    let mut msg = DialogMessage::new();
+    msg.set_header(c"Captured frames", 0, 0, Align::Left, Align::Top);
+    msg.set_text(text_cstr, 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(Some(c"<"), Some(c"Add"), Some(c">"));
+
+    match dialogs.show_message(&msg) {
+        DialogMessageButton::Left => Action::PrevCommand,
+        DialogMessageButton::Center => Action::AddBlind,
+        DialogMessageButton::Right => Action::NextCommand,
+        DialogMessageButton::Back => Action::Back,
+    }
+}
 
-    // Copy name
-    for &b in blind.name.as_bytes() {
-        if pos >= 38 {
-            break;
+/// Ask what to do about a state file that wouldn't load: restore the newest
+/// backup, start empty, or exit and leave things as they are. `None` to exit.
+///
+/// Whatever is chosen is saved straight away, so the next start doesn't ask again.
+/// A file too big for memory is fine and wasn't moved aside, so any save
+/// would start rotating it off the card; then exiting is the only way out.
+fn recovery_dialog(dialogs: &mut DialogsApp, error: LoadError) -> Option<SomfyState> {
+    let text = match error {
+        LoadError::Open => c"Couldn't open state file\nIt was kept aside",
+        LoadError::WrongFileType => c"Not a Somfy state file\nIt was kept aside",
+        LoadError::UnsupportedVersion(_) => c"From a newer app version\nIt was kept aside",
+        LoadError::Truncated => c"State file is cut short\nIt was kept aside",
+        LoadError::BadAddress => c"A blind's address is bad\nIt was kept aside",
+        LoadError::OutOfMemory => {
+            show_notice(
+                dialogs,
+                c"Too many blinds",
+                c"Not enough memory to load\nthem all. File left as is",
+            );
+            return None;
         }
-        buf[pos] = b;
-        pos += 1;
+    };
+    let backup = storage::recover_state();
+
+    let mut msg = DialogMessage::new();
+    msg.set_header(c"Blinds unreadable", 0, 0, Align::Left, Align::Top);
+    msg.set_text(text, 0, 26, Align::Left, Align::Top);
+    let restore = backup.is_some().then_some(c"Backup");
+    msg.set_buttons(Some(c"Exit"), restore, Some(c"Empty"));
+
+    let state = match dialogs.show_message(&msg) {
+        DialogMessageButton::Center => backup?,
+        DialogMessageButton::Right => SomfyState::new(),
+        _ => return None,
+    };
+    let _ = storage::save_state(&state);
+    Some(state)
+}
+
+/// Show a one-line message with an OK button.
+fn show_notice(dialogs: &mut DialogsApp, header: &CStr, text: &CStr) {
+    let mut msg = DialogMessage::new();
+    msg.set_header(header, 0, 0, Align::Left, Align::Top);
+    msg.set_text(text, 0, 26, Align::Left, Align::Top);
+    msg.set_buttons(None, Some(c"OK"), None);
+    dialogs.show_message(&msg);
+}
+
+/// `show_notice` for text made up as the app runs, like a file path.
+fn show_notice_text(dialogs: &mut DialogsApp, header: &CStr, text: &str) {
+    // Our text never holds a NUL; if it did, an empty notice says as much
+    let text = CString::new(text).unwrap_or_default();
+    show_notice(dialogs, header, &text);
+}
+
+/// Add a new blind and save it, or say why there's no room for one.
+fn new_blind(dialogs: &mut DialogsApp, state: &mut SomfyState) {
+    if add_blind(state) {
+        let _ = storage::save_state(state);
+    } else {
+        show_notice(dialogs, c"Add Blind", c"No room for more blinds");
+    }
+}
+
+/// Add a new blind with auto-generated name and the first address no other
+/// blind has. False if there's no room for it.
+fn add_blind(state: &mut SomfyState) -> bool {
+    let index = state.blinds.len();
+    let Some(address) = state.unused_address() else {
+        return false;
+    };
+
+    let blind = SomfyBlind::new(blind_name(index), address, 1);
+    if state.add(blind).is_err() {
+        return false;
     }
+    flipperzero::info!("Added blind {} at address {}", index + 1, address.get());
+    true
+}
+
+/// Add a blind that takes over from a captured remote.
+fn import_blind(
+    state: &mut SomfyState,
+    frame: SomfyFrame,
+    frequency: Option<u32>,
+    preset: Option<Preset>,
+) -> Result<(), AddError> {
+    let index = state.blinds.len();
+    let mut blind = SomfyBlind::continuing(blind_name(index), frame);
+    blind.tune(frequency, preset);
+    state.add(blind)?;
+    flipperzero::info!("Imported blind {} at address {}", index + 1, frame.address().get());
+    Ok(())
+}
+
+/// Auto-generated name for the blind at `index`: "Blind 1", "Blind 2", ...
+fn blind_name(index: usize) -> heapless::String<MAX_NAME_LEN> {
+    let mut name = heapless::String::<MAX_NAME_LEN>::new();
+    let _ = write!(name, "Blind {}", index + 1);
+    name
+}
 
-    // Null terminator
-    buf[pos] = 0;
-    pos
+/// Remove a blind by index, shifting others down.
+fn remove_blind(state: &mut SomfyState, index: usize) {
+    if index < state.blinds.len() {
+        state.blinds.remove(index);
+        flipperzero::info!("Removed blind {}", index);
+    }
 }
@@ -7,7 +7,7 @@ extern crate alloc;
 
 use alloc::vec::Vec;
 
-// Timing constants (in microseconds) — must match the C implementation exactly
+// Default timing constants (in microseconds) — must match the C implementation exactly
 const SOMFY_HALF_SYMBOL_US: u32 = 604;
 const SOMFY_WAKEUP_HIGH: u32 = 9415;
 const SOMFY_WAKEUP_LOW: u32 = 89565;
@@ -17,7 +17,88 @@ const SOMFY_SW_SYNC_HIGH: u32 = 4550;
 const SOMFY_SW_SYNC_LOW: u32 = 604;
 const SOMFY_INTER_FRAME_GAP: u32 = 30415;
 
-const MAX_TIMINGS: usize = 600;
+/// Longest pulse a timing profile may ask for. The longest real one, the
+/// wakeup's low, is under 90 ms; the cap keeps a whole frame's sums in `u32`.
+pub const MAX_PULSE_US: u32 = 100_000;
+
+/// Pulse lengths for a Somfy RTS transmission, in microseconds.
+///
+/// The default profile matches the C app exactly. Some motors and clone
+/// receivers are picky, so a blind can carry its own profile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SomfyTimings {
+    pub half_symbol: u32,
+    pub wakeup_high: u32,
+    pub wakeup_low: u32,
+    pub hw_sync_high: u32,
+    pub hw_sync_low: u32,
+    pub sw_sync_high: u32,
+    pub sw_sync_low: u32,
+    pub inter_frame_gap: u32,
+}
+
+impl SomfyTimings {
+    pub const DEFAULT: Self = Self {
+        half_symbol: SOMFY_HALF_SYMBOL_US,
+        wakeup_high: SOMFY_WAKEUP_HIGH,
+        wakeup_low: SOMFY_WAKEUP_LOW,
+        hw_sync_high: SOMFY_HW_SYNC_HIGH,
+        hw_sync_low: SOMFY_HW_SYNC_LOW,
+        sw_sync_high: SOMFY_SW_SYNC_HIGH,
+        sw_sync_low: SOMFY_SW_SYNC_LOW,
+        inter_frame_gap: SOMFY_INTER_FRAME_GAP,
+    };
+
+    /// Build a profile from the state file's array, in field order.
+    ///
+    /// Rejects zero-length pulses, which would end the transmission early,
+    /// and any over `MAX_PULSE_US`, which no motor expects.
+    pub fn from_array(values: [u32; 8]) -> Option<Self> {
+        if values.iter().any(|&us| us == 0 || us > MAX_PULSE_US) {
+            return None;
+        }
+        let [
+            half_symbol,
+            wakeup_high,
+            wakeup_low,
+            hw_sync_high,
+            hw_sync_low,
+            sw_sync_high,
+            sw_sync_low,
+            inter_frame_gap,
+        ] = values;
+        Some(Self {
+            half_symbol,
+            wakeup_high,
+            wakeup_low,
+            hw_sync_high,
+            hw_sync_low,
+            sw_sync_high,
+            sw_sync_low,
+            inter_frame_gap,
+        })
+    }
+
+    /// The state file's array, the inverse of `from_array`.
+    pub fn to_array(self) -> [u32; 8] {
+        [
+            self.half_symbol,
+            self.wakeup_high,
+            self.wakeup_low,
+            self.hw_sync_high,
+            self.hw_sync_low,
+            self.sw_sync_high,
+            self.sw_sync_low,
+            self.inter_frame_gap,
+        ]
+    }
+}
+
+impl Default for SomfyTimings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
 
 /// A level (high/low) and duration pair for sub-GHz transmission.
 ///
@@ -29,146 +110,818 @@ pub struct LevelDuration {
     pub duration: u32,
 }
 
-/// Somfy RTS command nibbles — each fits in the upper nibble of frame byte 1.
-#[repr(u8)]
+/// Somfy RTS commands — the documented Telis remote command table.
+///
+/// `My` and `Stop` are the same button and share nibble 0x1: a moving motor
+/// stops, a stationary one goes to its favourite position. They are separate
+/// variants so callers can say which one they mean, but a received 0x1 can't
+/// tell them apart and always decodes as `My`.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SomfyCommand {
-    Stop = 0x1,
-    Up = 0x2,
-    Down = 0x4,
-    Prog = 0x8,
+    My,
+    Stop,
+    Up,
+    MyUp,
+    Down,
+    MyDown,
+    UpDown,
+    MyUpDown,
+    Prog,
+    /// Enable the sun and wind detector (SUN + FLAG on a Telis Soliris).
+    SunFlag,
+    /// Disable the sun detector, wind only (FLAG on a Telis Soliris).
+    Flag,
+}
+
+impl SomfyCommand {
+    /// The nibble sent in the upper half of frame byte 1.
+    pub fn nibble(self) -> u8 {
+        match self {
+            Self::My | Self::Stop => 0x1,
+            Self::Up => 0x2,
+            Self::MyUp => 0x3,
+            Self::Down => 0x4,
+            Self::MyDown => 0x5,
+            Self::UpDown => 0x6,
+            Self::MyUpDown => 0x7,
+            Self::Prog => 0x8,
+            Self::SunFlag => 0x9,
+            Self::Flag => 0xA,
+        }
+    }
+
+    /// Map a command nibble from a received frame back to a command.
+    pub fn from_nibble(nibble: u8) -> Option<Self> {
+        match nibble {
+            0x1 => Some(Self::My),
+            0x2 => Some(Self::Up),
+            0x3 => Some(Self::MyUp),
+            0x4 => Some(Self::Down),
+            0x5 => Some(Self::MyDown),
+            0x6 => Some(Self::UpDown),
+            0x7 => Some(Self::MyUpDown),
+            0x8 => Some(Self::Prog),
+            0x9 => Some(Self::SunFlag),
+            0xA => Some(Self::Flag),
+            _ => None,
+        }
+    }
+}
+
+/// How many bits a frame carries on air.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum FrameLength {
+    /// The classic 56-bit frame sent by most Telis remotes.
+    #[default]
+    Standard,
+    /// An 80-bit frame — the 56-bit payload plus 3 trailing bytes — sent by
+    /// newer remotes and sensors, with its own HW sync counts.
+    Extended,
+}
+
+impl FrameLength {
+    pub const fn bits(self) -> usize {
+        match self {
+            Self::Standard => 56,
+            Self::Extended => 80,
+        }
+    }
+
+    /// Number of HW sync pulses before the SW sync of a frame.
+    fn hw_syncs(self, first_frame: bool) -> usize {
+        match (self, first_frame) {
+            (Self::Standard, true) => 2,
+            (Self::Standard, false) => 7,
+            (Self::Extended, true) => 12,
+            (Self::Extended, false) => 6,
+        }
+    }
+
+    /// Air time of one frame in microseconds, including the wakeup pulse for
+    /// the first frame and the preceding inter-frame gap for repeats.
+    fn frame_duration_us(self, timings: &SomfyTimings, first_frame: bool) -> u32 {
+        let lead_in = if first_frame {
+            timings.wakeup_high + timings.wakeup_low
+        } else {
+            timings.inter_frame_gap
+        };
+        let hw_sync_pair = timings.hw_sync_high + timings.hw_sync_low;
+        let hw_sync = self.hw_syncs(first_frame) as u32 * hw_sync_pair;
+        let sw_sync = timings.sw_sync_high + timings.sw_sync_low;
+        let data = self.bits() as u32 * 2 * timings.half_symbol;
+        lead_in + hw_sync + sw_sync + data
+    }
+
+    /// Air time of `frames` frames in microseconds — how long a transmission
+    /// of them keeps the radio busy.
+    pub fn airtime_us(self, timings: &SomfyTimings, frames: u32) -> u64 {
+        if frames == 0 {
+            return 0;
+        }
+        let first = self.frame_duration_us(timings, true) as u64;
+        let repeat = self.frame_duration_us(timings, false) as u64;
+        first + (frames as u64 - 1) * repeat
+    }
+}
+
+/// How the key byte (frame byte 0) is chosen for each frame.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum KeyPolicy {
+    /// Always `SomfyFrame::DEFAULT_KEY` (0xA0), like the C app.
+    #[default]
+    Fixed,
+    /// 0xA0 with the rolling code's low nibble (0xA0..0xAF), like genuine remotes.
+    Rolling,
+    /// A user-chosen key byte, e.g. to match a cloned remote.
+    Custom(u8),
+}
+
+impl KeyPolicy {
+    /// The key byte for a frame carrying `rolling_code`.
+    pub fn key(self, rolling_code: u16) -> u8 {
+        match self {
+            KeyPolicy::Fixed => SomfyFrame::DEFAULT_KEY,
+            KeyPolicy::Rolling => SomfyFrame::DEFAULT_KEY | (rolling_code as u8 & 0x0F),
+            KeyPolicy::Custom(key) => key,
+        }
+    }
+
+    /// The policy that reproduces a key byte seen on air alongside `rolling_code`.
+    ///
+    /// Used when cloning a captured remote. Genuine remotes are the common
+    /// case, so a code ending in 0 (where both agree on 0xA0) counts as
+    /// `Rolling`; keys neither policy would produce become `Custom`.
+    pub fn infer(key: u8, rolling_code: u16) -> Self {
+        if key == KeyPolicy::Rolling.key(rolling_code) {
+            KeyPolicy::Rolling
+        } else if key == KeyPolicy::Fixed.key(rolling_code) {
+            KeyPolicy::Fixed
+        } else {
+            KeyPolicy::Custom(key)
+        }
+    }
+
+    /// Parse the state file spelling: `fixed`, `rolling`, or a hex key byte like `A7`.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "fixed" => Some(KeyPolicy::Fixed),
+            "rolling" => Some(KeyPolicy::Rolling),
+            hex if hex.len() == 2 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                u8::from_str_radix(hex, 16).ok().map(KeyPolicy::Custom)
+            }
+            _ => None,
+        }
+    }
+
+    /// The state file spelling, the inverse of `parse`.
+    pub fn encode(self) -> heapless::String<8> {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        let mut text = heapless::String::new();
+        match self {
+            KeyPolicy::Fixed => {
+                let _ = text.push_str("fixed");
+            }
+            KeyPolicy::Rolling => {
+                let _ = text.push_str("rolling");
+            }
+            KeyPolicy::Custom(key) => {
+                let _ = text.push(char::from(HEX[(key >> 4) as usize]));
+                let _ = text.push(char::from(HEX[(key & 0x0F) as usize]));
+            }
+        }
+        text
+    }
+}
+
+/// How long a button is "pressed" — i.e. how many frames go on air.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Press {
+    /// A normal short press: a fixed number of frames.
+    Frames(u8),
+    /// A held button (Prog to pair, My to store the favourite position): keep
+    /// repeating until at least this many milliseconds have been sent.
+    HoldMs(u32),
+    /// A button held for as long as the user likes: repeat until released, like
+    /// a genuine remote, but send at least this many frames for a quick tap.
+    Held(u8),
 }
 
-/// Build a 7-byte Somfy RTS frame with the given command, rolling code, and address.
+impl Press {
+    /// Number of frames to send for this press — for `Held`, the fewest.
+    pub fn frame_count(self, length: FrameLength, timings: &SomfyTimings) -> u32 {
+        match self {
+            Press::Frames(count) | Press::Held(count) => count as u32,
+            Press::HoldMs(ms) => {
+                let hold_us = ms as u64 * 1000;
+                let first = length.frame_duration_us(timings, true) as u64;
+                let repeat = (length.frame_duration_us(timings, false) as u64).max(1);
+                let repeats = hold_us.saturating_sub(first).div_ceil(repeat);
+                (1 + repeats).min(u32::MAX as u64) as u32
+            }
+        }
+    }
+}
+
+/// Why a frame could not be built or parsed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FrameError {
+    /// The checksum nibble does not match the one computed over the frame.
+    BadChecksum { expected: u8, found: u8 },
+    /// The checksum is fine but the command nibble is not one we know.
+    UnknownCommand(u8),
+    /// The address does not fit in the frame's 24 bits.
+    AddressOutOfRange(u32),
+}
+
+/// A 24-bit Somfy remote address — the only kind that fits in a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SomfyAddress(u32);
+
+impl SomfyAddress {
+    pub const MAX: u32 = 0x00FF_FFFF;
+
+    /// Wrap an address, rejecting anything wider than 24 bits instead of truncating it.
+    pub fn new(address: u32) -> Result<Self, FrameError> {
+        if address > Self::MAX {
+            return Err(FrameError::AddressOutOfRange(address));
+        }
+        Ok(Self(address))
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// A plaintext (not obfuscated) 56-bit Somfy RTS frame.
 ///
-/// The frame layout is:
-///   [0] = 0xA0 (key/encryption byte)
+/// The byte layout is:
+///   [0] = key (see `KeyPolicy`)
 ///   [1] = (command << 4) | checksum
 ///   [2..3] = rolling code (big-endian)
 ///   [4..6] = address (big-endian, 24-bit)
 ///
-/// The checksum is the XOR of all nibbles (upper ^ lower) across all 7 bytes.
-pub fn build_frame(command: SomfyCommand, rolling_code: u16, address: u32) -> [u8; 7] {
-    let mut frame = [0u8; 7];
-
-    frame[0] = 0xA0;
-    frame[1] = (command as u8 & 0x0F) << 4;
-    frame[2] = (rolling_code >> 8) as u8;
-    frame[3] = rolling_code as u8;
-    frame[4] = (address >> 16) as u8;
-    frame[5] = (address >> 8) as u8;
-    frame[6] = address as u8;
-
-    // Checksum: XOR of all nibbles
+/// The checksum is chosen so the XOR of all nibbles (upper ^ lower) across all
+/// 7 bytes is zero. Fields are private so a frame can never carry a stale checksum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SomfyFrame {
+    key: u8,
+    command: SomfyCommand,
+    checksum: u8,
+    rolling_code: u16,
+    address: SomfyAddress,
+    extension: Option<[u8; 3]>,
+}
+
+impl SomfyFrame {
+    /// Key byte written by the C app, and the base of the rolling key.
+    pub const DEFAULT_KEY: u8 = 0xA0;
+
+    /// Build a frame with the default key and a freshly computed checksum.
+    pub fn new(command: SomfyCommand, rolling_code: u16, address: SomfyAddress) -> Self {
+        let mut frame = Self {
+            key: Self::DEFAULT_KEY,
+            command,
+            checksum: 0,
+            rolling_code,
+            address,
+            extension: None,
+        };
+        frame.checksum = checksum(&frame.to_bytes());
+        frame
+    }
+
+    /// Replace the key byte, keeping the checksum consistent.
+    pub fn with_key(mut self, key: u8) -> Self {
+        self.key = key;
+        self.checksum = 0;
+        self.checksum = checksum(&self.to_bytes());
+        self
+    }
+
+    /// Parse a plaintext frame, verifying the checksum and command nibble.
+    pub fn from_bytes(bytes: &[u8; 7]) -> Result<Self, FrameError> {
+        let found = bytes[1] & 0x0F;
+        let mut zeroed = *bytes;
+        zeroed[1] &= 0xF0;
+        let expected = checksum(&zeroed);
+        if found != expected {
+            return Err(FrameError::BadChecksum { expected, found });
+        }
+
+        let nibble = bytes[1] >> 4;
+        let command = SomfyCommand::from_nibble(nibble).ok_or(FrameError::UnknownCommand(nibble))?;
+
+        Ok(Self {
+            key: bytes[0],
+            command,
+            checksum: found,
+            rolling_code: u16::from_be_bytes([bytes[2], bytes[3]]),
+            address: SomfyAddress(u32::from_be_bytes([0, bytes[4], bytes[5], bytes[6]])),
+            extension: None,
+        })
+    }
+
+    /// Parse a frame as it came off the air, undoing the obfuscation first.
+    pub fn deobfuscate(bytes: &[u8; 7]) -> Result<Self, FrameError> {
+        let mut plain = *bytes;
+        deobfuscate(&mut plain);
+        Self::from_bytes(&plain)
+    }
+
+    /// Send this frame as an 80-bit extended frame with the given trailing bytes.
+    ///
+    /// The trailing bytes are sent as-is after the obfuscated payload and are
+    /// not covered by the checksum.
+    pub fn with_extension(mut self, extension: [u8; 3]) -> Self {
+        self.extension = Some(extension);
+        self
+    }
+
+    /// Serialize the 56-bit payload to the plaintext byte layout.
+    pub fn to_bytes(self) -> [u8; 7] {
+        let [_, a2, a1, a0] = self.address.0.to_be_bytes();
+        let [rc_hi, rc_lo] = self.rolling_code.to_be_bytes();
+        [
+            self.key,
+            ((self.command.nibble() & 0x0F) << 4) | (self.checksum & 0x0F),
+            rc_hi,
+            rc_lo,
+            a2,
+            a1,
+            a0,
+        ]
+    }
+
+    /// Serialize and obfuscate, ready for Manchester encoding.
+    pub fn obfuscated(self) -> [u8; 7] {
+        let mut bytes = self.to_bytes();
+        obfuscate(&mut bytes);
+        bytes
+    }
+
+    pub fn key(self) -> u8 {
+        self.key
+    }
+
+    pub fn command(self) -> SomfyCommand {
+        self.command
+    }
+
+    #[cfg(test)]
+    pub fn checksum(self) -> u8 {
+        self.checksum
+    }
+
+    pub fn rolling_code(self) -> u16 {
+        self.rolling_code
+    }
+
+    pub fn address(self) -> SomfyAddress {
+        self.address
+    }
+
+    pub fn extension(self) -> Option<[u8; 3]> {
+        self.extension
+    }
+
+    pub fn length(self) -> FrameLength {
+        match self.extension {
+            Some(_) => FrameLength::Extended,
+            None => FrameLength::Standard,
+        }
+    }
+}
+
+/// Why a timing stream could not be turned into a valid frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    /// No HW sync followed by a SW sync was found in the stream.
+    #[cfg(test)]
+    NoSync,
+    /// The stream ended before a full 56-bit payload was received.
+    Truncated,
+    /// A pulse did not fit the Manchester half-symbol grid.
+    BadManchester,
+    /// The bits decoded fine but do not form a valid frame.
+    Frame(FrameError),
+}
+
+impl From<FrameError> for DecodeError {
+    fn from(e: FrameError) -> Self {
+        DecodeError::Frame(e)
+    }
+}
+
+/// XOR of all nibbles (upper ^ lower) across all 7 bytes.
+fn checksum(bytes: &[u8; 7]) -> u8 {
     let mut checksum: u8 = 0;
-    for byte in &frame {
+    for byte in bytes {
         checksum ^= (byte >> 4) ^ (byte & 0x0F);
     }
-    frame[1] |= checksum & 0x0F;
-
-    frame
+    checksum & 0x0F
 }
 
 /// Obfuscate a frame by XOR-ing each byte with the previous one.
 ///
 /// This is the Somfy RTS "encryption" — each byte[i] ^= byte[i-1], starting from index 1.
-/// Must be applied after build_frame and before transmission.
+/// Must be applied to the plaintext bytes before transmission.
 pub fn obfuscate(frame: &mut [u8; 7]) {
     for i in 1..7 {
         frame[i] ^= frame[i - 1];
     }
 }
 
+/// Reverse `obfuscate` — each byte[i] ^= byte[i-1], walking backwards so the
+/// previous byte is still the obfuscated one when we use it.
+pub fn deobfuscate(frame: &mut [u8; 7]) {
+    for i in (1..7).rev() {
+        frame[i] ^= frame[i - 1];
+    }
+}
+
 /// Build a complete Somfy RTS transmission as a sequence of level/duration pairs.
 ///
+/// Collects a `TransmissionEncoder` into a buffer, for tests to compare
+/// against. The radio and file export pull from the encoder directly.
+#[cfg(test)]
+pub fn build_transmission(
+    frame: &SomfyFrame,
+    timings: &SomfyTimings,
+    repeats: u8,
+) -> Vec<LevelDuration> {
+    TransmissionEncoder::new(frame, timings, Some(repeats as u32)).collect()
+}
+
+/// Which part of a frame the encoder is emitting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Wakeup,
+    HwSync,
+    SwSync,
+    Data,
+    Gap,
+    Done,
+}
+
+/// Streaming Somfy RTS encoder — yields consolidated level/duration pairs one
+/// at a time without any buffers, so it can feed the TX callback directly.
+///
 /// This handles:
-/// - Wakeup pulse (first frame only) + HW sync
+/// - Wakeup pulse (first frame only) + HW sync (count depends on frame length)
 /// - SW sync
-/// - Manchester-encoded data (56 bits, MSB first)
+/// - Manchester-encoded data (56 or 80 bits, MSB first)
 /// - Inter-frame gaps between repeats
 /// - Consolidation of adjacent same-level entries (meow-rging them together)
-pub fn build_transmission(
-    command: SomfyCommand,
-    rolling_code: u16,
-    address: u32,
-    repeats: u8,
-) -> Vec<LevelDuration> {
-    let mut frame = build_frame(command, rolling_code, address);
-    obfuscate(&mut frame);
-
-    // First pass: build raw (unconsolidated) timings
-    let mut raw: Vec<LevelDuration> = Vec::with_capacity(MAX_TIMINGS);
-
-    for r in 0..repeats {
-        if r == 0 {
-            // Wakeup pulse
-            raw.push(LevelDuration { level: true, duration: SOMFY_WAKEUP_HIGH });
-            raw.push(LevelDuration { level: false, duration: SOMFY_WAKEUP_LOW });
-            // 2x HW sync
-            for _ in 0..2 {
-                raw.push(LevelDuration { level: true, duration: SOMFY_HW_SYNC_HIGH });
-                raw.push(LevelDuration { level: false, duration: SOMFY_HW_SYNC_LOW });
-            }
-        } else {
-            // 7x HW sync
-            for _ in 0..7 {
-                raw.push(LevelDuration { level: true, duration: SOMFY_HW_SYNC_HIGH });
-                raw.push(LevelDuration { level: false, duration: SOMFY_HW_SYNC_LOW });
-            }
+#[derive(Clone, Debug)]
+pub struct TransmissionEncoder {
+    bytes: [u8; 10],
+    length: FrameLength,
+    timings: SomfyTimings,
+    /// Total frames to send, or `None` to repeat until `stop_after` sets a
+    /// total or the radio drops the encoder.
+    frames: Option<u32>,
+    frame: u32,
+    phase: Phase,
+    /// Position within the current phase, one per unconsolidated pulse.
+    step: usize,
+    /// Unconsolidated pulse read ahead while merging same-level entries.
+    lookahead: Option<LevelDuration>,
+}
+
+impl TransmissionEncoder {
+    /// Encode `frames` copies of `frame`, or repeat endlessly if `frames` is `None`.
+    pub fn new(frame: &SomfyFrame, timings: &SomfyTimings, frames: Option<u32>) -> Self {
+        let mut bytes = [0u8; 10];
+        bytes[..7].copy_from_slice(&frame.obfuscated());
+        if let Some(extension) = frame.extension() {
+            bytes[7..].copy_from_slice(&extension);
         }
+        Self::from_air_bytes(bytes, frame.length(), timings, frames)
+    }
 
-        // SW sync
-        raw.push(LevelDuration { level: true, duration: SOMFY_SW_SYNC_HIGH });
-        raw.push(LevelDuration { level: false, duration: SOMFY_SW_SYNC_LOW });
+    /// Encode already obfuscated bytes — only the first `length` bits are sent.
+    fn from_air_bytes(
+        bytes: [u8; 10],
+        length: FrameLength,
+        timings: &SomfyTimings,
+        frames: Option<u32>,
+    ) -> Self {
+        Self {
+            bytes,
+            length,
+            timings: *timings,
+            frames,
+            frame: 0,
+            phase: if frames == Some(0) { Phase::Done } else { Phase::Wakeup },
+            step: 0,
+            lookahead: None,
+        }
+    }
 
-        // Manchester-encode 56 bits (7 bytes, MSB first)
-        // Bit 1 = rising edge: low then high
-        // Bit 0 = falling edge: high then low
-        for byte in &frame {
-            for bit_pos in (0..8).rev() {
-                let bit = (byte >> bit_pos) & 1;
-                if bit == 1 {
-                    raw.push(LevelDuration { level: false, duration: SOMFY_HALF_SYMBOL_US });
-                    raw.push(LevelDuration { level: true, duration: SOMFY_HALF_SYMBOL_US });
-                } else {
-                    raw.push(LevelDuration { level: true, duration: SOMFY_HALF_SYMBOL_US });
-                    raw.push(LevelDuration { level: false, duration: SOMFY_HALF_SYMBOL_US });
+    /// Finish the frame currently on air, then end the transmission — but
+    /// not before `frames` frames in all have gone out.
+    ///
+    /// Ends endless transmissions when a held button is released, and cuts
+    /// counted ones short when cancelled.
+    pub fn stop_after(&mut self, frames: u32) {
+        let frames = (self.frame + 1).max(frames);
+        self.frames = Some(self.frames.map_or(frames, |total| total.min(frames)));
+    }
+
+    fn is_last_frame(&self) -> bool {
+        self.frames.is_some_and(|frames| self.frame + 1 >= frames)
+    }
+
+    fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.step = 0;
+    }
+
+    /// Next unconsolidated pulse.
+    fn next_raw(&mut self) -> Option<LevelDuration> {
+        loop {
+            let t = &self.timings;
+            let step = self.step;
+            self.step += 1;
+
+            let (level, duration) = match self.phase {
+                Phase::Wakeup => match step {
+                    0 => (true, t.wakeup_high),
+                    1 => (false, t.wakeup_low),
+                    _ => {
+                        self.enter(Phase::HwSync);
+                        continue;
+                    }
+                },
+                // HW sync — 2x on the first frame and 7x on repeats (12x / 6x for 80-bit)
+                Phase::HwSync => {
+                    if step >= 2 * self.length.hw_syncs(self.frame == 0) {
+                        self.enter(Phase::SwSync);
+                        continue;
+                    }
+                    if step % 2 == 0 { (true, t.hw_sync_high) } else { (false, t.hw_sync_low) }
+                }
+                Phase::SwSync => match step {
+                    0 => (true, t.sw_sync_high),
+                    1 => (false, t.sw_sync_low),
+                    _ => {
+                        self.enter(Phase::Data);
+                        continue;
+                    }
+                },
+                // Manchester data, MSB first
+                // Bit 1 = rising edge: low then high
+                // Bit 0 = falling edge: high then low
+                Phase::Data => {
+                    let bit_index = step / 2;
+                    if bit_index >= self.length.bits() {
+                        let next = if self.is_last_frame() { Phase::Done } else { Phase::Gap };
+                        self.enter(next);
+                        continue;
+                    }
+                    let bit = (self.bytes[bit_index / 8] >> (7 - bit_index % 8)) & 1 == 1;
+                    let first_half = step % 2 == 0;
+                    (bit != first_half, t.half_symbol)
+                }
+                // Inter-frame gap (only between frames)
+                Phase::Gap => {
+                    if step == 0 {
+                        (false, t.inter_frame_gap)
+                    } else {
+                        self.frame += 1;
+                        // `stop_after` may have landed while the gap was going out
+                        let done = self.frames.is_some_and(|frames| self.frame >= frames);
+                        self.enter(if done { Phase::Done } else { Phase::HwSync });
+                        continue;
+                    }
                 }
+                Phase::Done => return None,
+            };
+
+            return Some(LevelDuration { level, duration });
+        }
+    }
+}
+
+impl Iterator for TransmissionEncoder {
+    type Item = LevelDuration;
+
+    /// Next consolidated pulse — adjacent same-level pulses are merged.
+    fn next(&mut self) -> Option<LevelDuration> {
+        let mut current = self.lookahead.take().or_else(|| self.next_raw())?;
+        while let Some(next) = self.next_raw() {
+            if next.level != current.level {
+                self.lookahead = Some(next);
+                break;
+            }
+            current.duration = current.duration.saturating_add(next.duration);
+        }
+        Some(current)
+    }
+}
+
+/// Whether a measured duration is within 25% of the expected one.
+fn near(duration: u32, expected: u32) -> bool {
+    duration.abs_diff(expected) <= expected / 4
+}
+
+/// Decode the first valid Somfy RTS frame found in a timing stream.
+///
+/// Scans for a HW sync low immediately followed by a SW sync high, then
+/// Manchester-decodes 56 or 80 bits, de-obfuscates the payload and verifies
+/// the checksum.
+/// Works on both raw and consolidated streams, so it accepts our own
+/// `build_transmission` output as well as sniffed captures. Pulses are
+/// matched against `profile`. If every sync candidate fails, the error from
+/// the first one is returned.
+#[cfg(test)]
+pub fn decode_transmission(
+    timings: &[LevelDuration],
+    profile: &SomfyTimings,
+) -> Result<SomfyFrame, DecodeError> {
+    let mut first_error = None;
+
+    for i in 1..timings.len() {
+        let sync_low = timings[i - 1];
+        let sync_high = timings[i];
+        if sync_low.level
+            || !near(sync_low.duration, profile.hw_sync_low)
+            || !sync_high.level
+            || !near(sync_high.duration, profile.sw_sync_high)
+        {
+            continue;
+        }
+
+        match decode_frame_at(&timings[i + 1..], profile) {
+            Ok(frame) => return Ok(frame),
+            Err(e) => {
+                first_error.get_or_insert(e);
             }
         }
+    }
+
+    Err(first_error.unwrap_or(DecodeError::NoSync))
+}
+
+/// Decode every frame in a capture, e.g. a RAW recording of several presses.
+///
+/// A press repeats the same frame several times; consecutive repeats are
+/// reported once. Syncs that don't lead to a valid frame are skipped.
+pub fn decode_all(timings: &[LevelDuration], profile: &SomfyTimings) -> Vec<SomfyFrame> {
+    let mut frames: Vec<SomfyFrame> = Vec::new();
+
+    for i in 1..timings.len() {
+        let sync_low = timings[i - 1];
+        let sync_high = timings[i];
+        if sync_low.level
+            || !near(sync_low.duration, profile.hw_sync_low)
+            || !sync_high.level
+            || !near(sync_high.duration, profile.sw_sync_high)
+        {
+            continue;
+        }
 
-        // Inter-frame gap (except after last frame)
-        if r + 1 < repeats {
-            raw.push(LevelDuration { level: false, duration: SOMFY_INTER_FRAME_GAP });
+        if let Ok(frame) = decode_frame_at(&timings[i + 1..], profile) {
+            if frames.last() != Some(&frame) {
+                frames.push(frame);
+            }
         }
     }
 
-    // Consolidation: merge adjacent entries with the same level
-    let mut consolidated: Vec<LevelDuration> = Vec::with_capacity(raw.len());
+    frames
+}
+
+/// Decode one frame from the pulses right after a SW sync high.
+fn decode_frame_at(
+    pulses: &[LevelDuration],
+    profile: &SomfyTimings,
+) -> Result<SomfyFrame, DecodeError> {
+    let (bytes, length) = manchester_decode(pulses, profile)?;
+    let mut payload = [0u8; 7];
+    payload.copy_from_slice(&bytes[..7]);
+    let frame = SomfyFrame::deobfuscate(&payload)?;
+
+    Ok(match length {
+        FrameLength::Standard => frame,
+        FrameLength::Extended => frame.with_extension([bytes[7], bytes[8], bytes[9]]),
+    })
+}
+
+/// Manchester-decode up to 80 bits from the pulses following a SW sync high.
+///
+/// Consolidation means one pulse can span one or two half-symbols, so each
+/// pulse is split back into half-symbols and paired up. The first pulse starts
+/// with the SW sync low, which is subtracted before splitting. A long low can
+/// only be the last half of the final bit merged into the inter-frame gap, so
+/// it ends the frame.
+///
+/// Anything from 56 bits up to (but not including) 80 is a standard frame
+/// followed by whatever the receiver picked up next; only a full 80 bits makes
+/// an extended frame.
+fn manchester_decode(
+    pulses: &[LevelDuration],
+    profile: &SomfyTimings,
+) -> Result<([u8; 10], FrameLength), DecodeError> {
+    const STANDARD_BITS: usize = FrameLength::Standard.bits();
+    const EXTENDED_BITS: usize = FrameLength::Extended.bits();
+    let half = profile.half_symbol;
+
+    let mut frame = [0u8; 10];
+    let mut bits = 0usize;
+    let mut first_half: Option<bool> = None;
+    let mut error = None;
+
+    'pulses: for (i, pulse) in pulses.iter().enumerate() {
+        let mut duration = pulse.duration;
+        if i == 0 {
+            if pulse.level {
+                error = Some(DecodeError::BadManchester);
+                break;
+            }
+            duration = duration.saturating_sub(profile.sw_sync_low);
+            if duration < half / 2 {
+                // Just the SW sync low — the first bit starts high
+                continue;
+            }
+        }
+
+        let (halves, last) = match duration {
+            d if d < half / 2 => (0, true),
+            d if d < half * 3 / 2 => (1, false),
+            d if d < half * 5 / 2 => (2, false),
+            _ if !pulse.level => (1, true),
+            _ => (0, true),
+        };
+        if halves == 0 {
+            error = Some(DecodeError::BadManchester);
+            break;
+        }
 
-    for entry in raw.iter() {
-        if let Some(last) = consolidated.last_mut() {
-            if last.level == entry.level {
-                last.duration += entry.duration;
+        for _ in 0..halves {
+            let Some(first) = first_half.take() else {
+                first_half = Some(pulse.level);
                 continue;
+            };
+            // Bit 1 = low then high, bit 0 = high then low
+            let bit = match (first, pulse.level) {
+                (false, true) => 1,
+                (true, false) => 0,
+                _ => {
+                    error = Some(DecodeError::BadManchester);
+                    break 'pulses;
+                }
+            };
+            frame[bits / 8] |= bit << (7 - bits % 8);
+            bits += 1;
+            if bits == EXTENDED_BITS {
+                return Ok((frame, FrameLength::Extended));
             }
         }
-        consolidated.push(*entry);
+
+        if last {
+            break;
+        }
     }
 
-    consolidated
+    if bits >= STANDARD_BITS {
+        Ok((frame, FrameLength::Standard))
+    } else {
+        Err(error.unwrap_or(DecodeError::Truncated))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const TIMINGS: &SomfyTimings = &SomfyTimings::DEFAULT;
+
+    const ALL_COMMANDS: [SomfyCommand; 11] = [
+        SomfyCommand::My,
+        SomfyCommand::Stop,
+        SomfyCommand::Up,
+        SomfyCommand::MyUp,
+        SomfyCommand::Down,
+        SomfyCommand::MyDown,
+        SomfyCommand::UpDown,
+        SomfyCommand::MyUpDown,
+        SomfyCommand::Prog,
+        SomfyCommand::SunFlag,
+        SomfyCommand::Flag,
+    ];
+
+    fn addr(address: u32) -> SomfyAddress {
+        SomfyAddress::new(address).unwrap()
+    }
+
     #[test]
     fn test_build_frame_checksum() {
         // Verify frame structure and that checksum nibble is correctly computed
-        let frame = build_frame(SomfyCommand::Up, 0x0042, 0xCAFE00);
+        let built = SomfyFrame::new(SomfyCommand::Up, 0x0042, addr(0xCAFE00));
+        let frame = built.to_bytes();
         assert_eq!(frame[0], 0xA0, "key byte should be 0xA0");
-        assert_eq!(frame[1] >> 4, SomfyCommand::Up as u8, "command nibble");
+        assert_eq!(frame[1] >> 4, SomfyCommand::Up.nibble(), "command nibble");
         assert_eq!(frame[2], 0x00, "rolling code high byte");
         assert_eq!(frame[3], 0x42, "rolling code low byte");
         assert_eq!(frame[4], 0xCA, "address byte 2");
@@ -181,11 +934,12 @@ mod tests {
             check ^= (byte >> 4) ^ (byte & 0x0F);
         }
         assert_eq!(check, 0, "checksum should make all nibbles XOR to 0");
+        assert_eq!(built.checksum(), frame[1] & 0x0F, "checksum nibble");
     }
 
     #[test]
     fn test_obfuscate() {
-        let mut frame = build_frame(SomfyCommand::Stop, 0x0001, 0x123456);
+        let mut frame = SomfyFrame::new(SomfyCommand::My, 0x0001, addr(0x123456)).to_bytes();
         let original = frame;
         obfuscate(&mut frame);
 
@@ -199,13 +953,15 @@ mod tests {
 
     #[test]
     fn test_build_transmission_not_empty() {
-        let timings = build_transmission(SomfyCommand::Up, 1, 0xABCDEF, 3);
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, addr(0xABCDEF));
+        let timings = build_transmission(&frame, TIMINGS, 3);
         assert!(!timings.is_empty(), "transmission should produce timings");
     }
 
     #[test]
     fn test_build_transmission_starts_with_wakeup() {
-        let timings = build_transmission(SomfyCommand::Down, 42, 0x112233, 1);
+        let frame = SomfyFrame::new(SomfyCommand::Down, 42, addr(0x112233));
+        let timings = build_transmission(&frame, TIMINGS, 1);
         // First entry should be the wakeup high pulse
         assert_eq!(timings[0].level, true);
         assert_eq!(timings[0].duration, SOMFY_WAKEUP_HIGH);
@@ -213,7 +969,8 @@ mod tests {
 
     #[test]
     fn test_consolidation_merges_adjacent_levels() {
-        let timings = build_transmission(SomfyCommand::Up, 1, 0x000001, 1);
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x000001));
+        let timings = build_transmission(&frame, TIMINGS, 1);
         // After consolidation, no two adjacent entries should have the same level
         for window in timings.windows(2) {
             assert_ne!(
@@ -225,7 +982,8 @@ mod tests {
 
     #[test]
     fn test_single_frame_no_trailing_gap() {
-        let timings = build_transmission(SomfyCommand::Prog, 99, 0xFEDCBA, 1);
+        let frame = SomfyFrame::new(SomfyCommand::Prog, 99, addr(0xFEDCBA));
+        let timings = build_transmission(&frame, TIMINGS, 1);
         // Last entry should NOT be the inter-frame gap (only added between frames)
         let last = timings.last().unwrap();
         // With a single repeat, the last timing comes from manchester data, not a gap
@@ -235,9 +993,389 @@ mod tests {
     #[test]
     fn test_all_commands() {
         // Just make sure all command variants produce valid frames — no panics, nyaa~
-        for cmd in [SomfyCommand::Stop, SomfyCommand::Up, SomfyCommand::Down, SomfyCommand::Prog] {
-            let frame = build_frame(cmd, 0, 0);
-            assert_eq!(frame[1] >> 4, cmd as u8);
+        for cmd in ALL_COMMANDS {
+            let frame = SomfyFrame::new(cmd, 0, addr(0)).to_bytes();
+            assert_eq!(frame[1] >> 4, cmd.nibble());
+        }
+    }
+
+    #[test]
+    fn test_command_nibbles() {
+        for cmd in ALL_COMMANDS {
+            let expected = if cmd == SomfyCommand::Stop { SomfyCommand::My } else { cmd };
+            assert_eq!(SomfyCommand::from_nibble(cmd.nibble()), Some(expected));
+        }
+        assert_eq!(SomfyCommand::Stop.nibble(), SomfyCommand::My.nibble());
+        assert_eq!(SomfyCommand::from_nibble(0x0), None);
+        assert_eq!(SomfyCommand::from_nibble(0xB), None);
+    }
+
+    #[test]
+    fn test_address_out_of_range() {
+        assert_eq!(SomfyAddress::new(0xFFFFFF).map(SomfyAddress::get), Ok(0xFFFFFF));
+        assert_eq!(
+            SomfyAddress::new(0x1000000),
+            Err(FrameError::AddressOutOfRange(0x1000000))
+        );
+    }
+
+    #[test]
+    fn test_frame_bytes_round_trip() {
+        let frame = SomfyFrame::new(SomfyCommand::Down, 0x1234, addr(0xABCDEF));
+        assert_eq!(SomfyFrame::from_bytes(&frame.to_bytes()), Ok(frame));
+        assert_eq!(SomfyFrame::deobfuscate(&frame.obfuscated()), Ok(frame));
+        assert_eq!(frame.rolling_code(), 0x1234);
+        assert_eq!(frame.address().get(), 0xABCDEF);
+    }
+
+    #[test]
+    fn test_from_bytes_bad_checksum() {
+        let mut bytes = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x123456)).to_bytes();
+        let found = bytes[1] & 0x0F;
+        bytes[1] ^= 0x03;
+        assert_eq!(
+            SomfyFrame::from_bytes(&bytes),
+            Err(FrameError::BadChecksum { expected: found, found: found ^ 0x03 })
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_unknown_command() {
+        // 0xB is not in the command table; fix up the checksum so only the nibble is wrong
+        let mut bytes = SomfyFrame::new(SomfyCommand::My, 1, addr(0x123456)).to_bytes();
+        bytes[1] ^= 0xA0 | 0x0A;
+        assert_eq!(SomfyFrame::from_bytes(&bytes), Err(FrameError::UnknownCommand(0xB)));
+    }
+
+    #[test]
+    fn test_deobfuscate_reverses_obfuscate() {
+        let original = SomfyFrame::new(SomfyCommand::Down, 0x1234, addr(0xABCDEF)).to_bytes();
+        let mut frame = original;
+        obfuscate(&mut frame);
+        deobfuscate(&mut frame);
+        assert_eq!(frame, original);
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        for cmd in [SomfyCommand::My, SomfyCommand::Up, SomfyCommand::MyDown, SomfyCommand::Flag] {
+            for repeats in 1..=4 {
+                let frame = SomfyFrame::new(cmd, 0xBEEF, addr(0x123456));
+                let timings = build_transmission(&frame, TIMINGS, repeats);
+                assert_eq!(decode_transmission(&timings, TIMINGS), Ok(frame));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_tolerates_jitter() {
+        // Real receivers never see exact durations — wobble every pulse by ~10%
+        let frame = SomfyFrame::new(SomfyCommand::Up, 7, addr(0x0A0B0C));
+        let mut timings = build_transmission(&frame, TIMINGS, 1);
+        for (i, t) in timings.iter_mut().enumerate() {
+            if i % 2 == 0 {
+                t.duration += t.duration / 10;
+            } else {
+                t.duration -= t.duration / 10;
+            }
         }
+        assert_eq!(decode_transmission(&timings, TIMINGS), Ok(frame));
+    }
+
+    #[test]
+    fn test_decode_no_sync() {
+        assert_eq!(decode_transmission(&[], TIMINGS), Err(DecodeError::NoSync));
+        let noise = [
+            LevelDuration { level: true, duration: 300 },
+            LevelDuration { level: false, duration: 300 },
+        ];
+        assert_eq!(decode_transmission(&noise, TIMINGS), Err(DecodeError::NoSync));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x123456));
+        let timings = build_transmission(&frame, TIMINGS, 1);
+        let cut = &timings[..timings.len() - 10];
+        assert_eq!(decode_transmission(cut, TIMINGS), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn test_decode_bad_checksum() {
+        let mut bytes = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x123456)).to_bytes();
+        bytes[6] ^= 0x01;
+        obfuscate(&mut bytes);
+        let mut air = [0u8; 10];
+        air[..7].copy_from_slice(&bytes);
+        let timings: Vec<_> =
+            TransmissionEncoder::from_air_bytes(air, FrameLength::Standard, TIMINGS, Some(1))
+                .collect();
+        assert!(matches!(
+            decode_transmission(&timings, TIMINGS),
+            Err(DecodeError::Frame(FrameError::BadChecksum { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_decode_all_lists_each_press_once() {
+        let up = SomfyFrame::new(SomfyCommand::Up, 0x0010, addr(0x123456));
+        let down = SomfyFrame::new(SomfyCommand::Down, 0x0011, addr(0x123456));
+        let other = SomfyFrame::new(SomfyCommand::My, 0x0300, addr(0xABCDEF))
+            .with_extension([1, 2, 3]);
+
+        let mut timings = build_transmission(&up, TIMINGS, 4);
+        timings.extend(build_transmission(&down, TIMINGS, 3));
+        timings.extend(build_transmission(&other, TIMINGS, 2));
+        assert_eq!(decode_all(&timings, TIMINGS), [up, down, other]);
+        assert!(decode_all(&[], TIMINGS).is_empty());
+    }
+
+    #[test]
+    fn test_extended_round_trip() {
+        let frame = SomfyFrame::new(SomfyCommand::Up, 0x0102, addr(0x345678))
+            .with_extension([0xDE, 0xAD, 0x42]);
+        assert_eq!(frame.length(), FrameLength::Extended);
+        for repeats in 1..=3 {
+            let timings = build_transmission(&frame, TIMINGS, repeats);
+            assert_eq!(decode_transmission(&timings, TIMINGS), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn test_extended_sync_counts() {
+        let count_hw_syncs = |timings: &[LevelDuration]| {
+            timings.iter().filter(|t| t.level && t.duration == SOMFY_HW_SYNC_HIGH).count()
+        };
+        let frame = SomfyFrame::new(SomfyCommand::Down, 5, addr(0x123456));
+        assert_eq!(count_hw_syncs(&build_transmission(&frame, TIMINGS, 2)), 2 + 7);
+        let extended = frame.with_extension([0; 3]);
+        assert_eq!(count_hw_syncs(&build_transmission(&extended, TIMINGS, 2)), 12 + 6);
+    }
+
+    #[test]
+    fn test_extended_truncated_decodes_as_standard() {
+        // Lose the tail of an 80-bit frame — the 56-bit payload is still good
+        let frame = SomfyFrame::new(SomfyCommand::My, 9, addr(0x0F0F0F)).with_extension([0xFF; 3]);
+        let timings = build_transmission(&frame, TIMINGS, 1);
+        let cut = &timings[..timings.len() - 8];
+        let decoded = decode_transmission(cut, TIMINGS).unwrap();
+        assert_eq!(decoded.length(), FrameLength::Standard);
+        assert_eq!(decoded.rolling_code(), 9);
+    }
+
+    #[test]
+    fn test_press_frames_is_exact() {
+        assert_eq!(Press::Frames(4).frame_count(FrameLength::Standard, TIMINGS), 4);
+        assert_eq!(Press::Frames(4).frame_count(FrameLength::Extended, TIMINGS), 4);
+    }
+
+    #[test]
+    fn test_hold_covers_requested_time() {
+        for length in [FrameLength::Standard, FrameLength::Extended] {
+            for ms in [0, 500, 2500, 5000] {
+                let frames = Press::HoldMs(ms).frame_count(length, TIMINGS);
+                let frame = SomfyFrame::new(SomfyCommand::Prog, 1, addr(0x123456));
+                let frame = match length {
+                    FrameLength::Standard => frame,
+                    FrameLength::Extended => frame.with_extension([0; 3]),
+                };
+                let air_time = |frames| {
+                    TransmissionEncoder::new(&frame, TIMINGS, Some(frames))
+                        .map(|t| t.duration)
+                        .sum::<u32>()
+                };
+                // Long enough to cover the hold, but no more than one frame over
+                assert!(air_time(frames) >= ms * 1000, "{ms} ms hold came up short");
+                assert!(frames == 1 || air_time(frames - 1) < ms * 1000);
+            }
+        }
+    }
+
+    #[test]
+    fn test_airtime_matches_encoder() {
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x123456));
+        for length in [FrameLength::Standard, FrameLength::Extended] {
+            let frame = match length {
+                FrameLength::Standard => frame,
+                FrameLength::Extended => frame.with_extension([0; 3]),
+            };
+            for frames in [0, 1, 4, 20] {
+                let sent: u64 = TransmissionEncoder::new(&frame, TIMINGS, Some(frames))
+                    .map(|t| t.duration as u64)
+                    .sum();
+                assert_eq!(length.airtime_us(TIMINGS, frames), sent, "{frames} frames");
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_hold_does_not_overflow() {
+        // ~49 days of holding — silly, but must not wrap around to a short press
+        let frames = Press::HoldMs(u32::MAX).frame_count(FrameLength::Standard, TIMINGS);
+        assert!(frames > 1_000_000);
+    }
+
+    #[test]
+    fn test_key_policy() {
+        assert_eq!(KeyPolicy::Fixed.key(0x1237), 0xA0);
+        assert_eq!(KeyPolicy::Rolling.key(0x1237), 0xA7);
+        assert_eq!(KeyPolicy::Rolling.key(0x0040), 0xA0);
+        assert_eq!(KeyPolicy::Custom(0x5C).key(0x1237), 0x5C);
+    }
+
+    #[test]
+    fn test_rolling_key_reference_frames() {
+        // Consecutive Up presses from one remote (address 0x1A2B3C), plaintext
+        // and on-air bytes — the key nibble follows the rolling code and wraps
+        let reference: [(u16, [u8; 7], [u8; 7]); 4] = [
+            (
+                0x0041,
+                [0xA1, 0x21, 0x00, 0x41, 0x1A, 0x2B, 0x3C],
+                [0xA1, 0x80, 0x80, 0xC1, 0xDB, 0xF0, 0xCC],
+            ),
+            (
+                0x0042,
+                [0xA2, 0x21, 0x00, 0x42, 0x1A, 0x2B, 0x3C],
+                [0xA2, 0x83, 0x83, 0xC1, 0xDB, 0xF0, 0xCC],
+            ),
+            (
+                0x004F,
+                [0xAF, 0x21, 0x00, 0x4F, 0x1A, 0x2B, 0x3C],
+                [0xAF, 0x8E, 0x8E, 0xC1, 0xDB, 0xF0, 0xCC],
+            ),
+            (
+                0x0050,
+                [0xA0, 0x20, 0x00, 0x50, 0x1A, 0x2B, 0x3C],
+                [0xA0, 0x80, 0x80, 0xD0, 0xCA, 0xE1, 0xDD],
+            ),
+        ];
+        for (rolling_code, plain, on_air) in reference {
+            let frame = SomfyFrame::new(SomfyCommand::Up, rolling_code, addr(0x1A2B3C))
+                .with_key(KeyPolicy::Rolling.key(rolling_code));
+            assert_eq!(frame.to_bytes(), plain);
+            assert_eq!(frame.obfuscated(), on_air);
+            assert_eq!(SomfyFrame::deobfuscate(&on_air), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn test_key_policy_infer() {
+        assert_eq!(KeyPolicy::infer(0xA7, 0x1237), KeyPolicy::Rolling);
+        assert_eq!(KeyPolicy::infer(0xA0, 0x1230), KeyPolicy::Rolling);
+        assert_eq!(KeyPolicy::infer(0xA0, 0x1237), KeyPolicy::Fixed);
+        assert_eq!(KeyPolicy::infer(0x5C, 0x1237), KeyPolicy::Custom(0x5C));
+    }
+
+    #[test]
+    fn test_key_policy_text_round_trip() {
+        for policy in [KeyPolicy::Fixed, KeyPolicy::Rolling, KeyPolicy::Custom(0xA7)] {
+            assert_eq!(KeyPolicy::parse(&policy.encode()), Some(policy));
+        }
+        assert_eq!(KeyPolicy::parse("a7"), Some(KeyPolicy::Custom(0xA7)));
+        assert_eq!(KeyPolicy::parse("A"), None);
+        assert_eq!(KeyPolicy::parse("+7"), None);
+        assert_eq!(KeyPolicy::parse("random"), None);
+    }
+
+    #[test]
+    fn test_default_timings_match_c_app() {
+        let timings = SomfyTimings::default();
+        assert_eq!(timings.half_symbol, 604);
+        assert_eq!(timings.wakeup_high, 9415);
+        assert_eq!(timings.wakeup_low, 89565);
+        assert_eq!(timings.hw_sync_high, 2416);
+        assert_eq!(timings.hw_sync_low, 2416);
+        assert_eq!(timings.sw_sync_high, 4550);
+        assert_eq!(timings.sw_sync_low, 604);
+        assert_eq!(timings.inter_frame_gap, 30415);
+    }
+
+    #[test]
+    fn test_custom_timings_used_and_decodable() {
+        let timings = SomfyTimings {
+            half_symbol: 640,
+            sw_sync_low: 900,
+            inter_frame_gap: 27000,
+            ..SomfyTimings::DEFAULT
+        };
+        let frame = SomfyFrame::new(SomfyCommand::Down, 0x0BAD, addr(0x00C0DE));
+        let stream = build_transmission(&frame, &timings, 2);
+        assert!(stream.iter().any(|t| !t.level && t.duration == 27000));
+        assert!(stream.iter().all(|t| t.duration != SOMFY_HALF_SYMBOL_US));
+        assert_eq!(decode_transmission(&stream, &timings), Ok(frame));
+    }
+
+    #[test]
+    fn test_timings_array_round_trip() {
+        let timings = SomfyTimings { wakeup_high: 10_000, ..SomfyTimings::DEFAULT };
+        assert_eq!(SomfyTimings::from_array(timings.to_array()), Some(timings));
+        let mut zeroed = timings.to_array();
+        zeroed[3] = 0;
+        assert_eq!(SomfyTimings::from_array(zeroed), None);
+
+        let mut longest = timings.to_array();
+        longest.fill(MAX_PULSE_US);
+        let longest = SomfyTimings::from_array(longest).expect("at the cap is fine");
+        // Even then a frame's air time can't overflow
+        assert!(FrameLength::Extended.airtime_us(&longest, 2) > 0);
+        let mut corrupt = timings.to_array();
+        corrupt[2] = u32::MAX;
+        assert_eq!(SomfyTimings::from_array(corrupt), None);
+    }
+
+    #[test]
+    fn test_encoder_zero_frames_is_empty() {
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x123456));
+        assert_eq!(TransmissionEncoder::new(&frame, TIMINGS, Some(0)).next(), None);
+    }
+
+    #[test]
+    fn test_encoder_stop_matches_fixed_count() {
+        let frame = SomfyFrame::new(SomfyCommand::My, 77, addr(0x424242));
+        let fixed: Vec<_> = TransmissionEncoder::new(&frame, TIMINGS, Some(3)).collect();
+
+        // Pull from an endless stream and "release the button" partway through frame 3
+        let mut endless = TransmissionEncoder::new(&frame, TIMINGS, None);
+        let mut streamed = Vec::new();
+        for _ in 0..fixed.len() - 20 {
+            streamed.push(endless.next().unwrap());
+        }
+        endless.stop_after(0);
+        streamed.extend(endless);
+        assert_eq!(streamed, fixed);
+    }
+
+    #[test]
+    fn test_encoder_stop_during_gap_ends_cleanly() {
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, addr(0x123456));
+        let single: Vec<_> = TransmissionEncoder::new(&frame, TIMINGS, Some(1)).collect();
+
+        // Pull one frame plus the inter-frame gap, then stop
+        let mut endless = TransmissionEncoder::new(&frame, TIMINGS, None);
+        let mut streamed: Vec<_> = endless.by_ref().take(single.len()).collect();
+        endless.stop_after(0);
+        streamed.extend(endless);
+        assert_eq!(&streamed[..single.len() - 1], &single[..single.len() - 1]);
+        assert!(streamed.len() <= single.len() + 1);
+        assert_eq!(decode_transmission(&streamed, TIMINGS), Ok(frame));
+    }
+
+    #[test]
+    fn test_encoder_stop_after_sends_minimum() {
+        let frame = SomfyFrame::new(SomfyCommand::Down, 5, addr(0x123456));
+        let fixed: Vec<_> = TransmissionEncoder::new(&frame, TIMINGS, Some(4)).collect();
+
+        // A quick tap: released during the first frame, but 4 frames still go out
+        let mut tapped = TransmissionEncoder::new(&frame, TIMINGS, None);
+        let mut streamed: Vec<_> = tapped.by_ref().take(10).collect();
+        tapped.stop_after(4);
+        streamed.extend(tapped);
+        assert_eq!(streamed, fixed);
+
+        // Never more than asked of a fixed count
+        let mut short = TransmissionEncoder::new(&frame, TIMINGS, Some(2));
+        short.stop_after(4);
+        let sent: u64 = short.map(|t| t.duration as u64).sum();
+        assert_eq!(sent, FrameLength::Standard.airtime_us(TIMINGS, 2));
     }
 }
@@ -0,0 +1,162 @@
+//! Lock-free pulse ring buffer — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! The Sub-GHz capture ISR pushes level/duration pairs in, the app pops them
+//! out. One producer, one consumer, and nobody ever waits — like a cat flap.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use crate::protocol::LevelDuration;
+
+/// High bit of a slot holds the level, the rest the duration in µs.
+const LEVEL_BIT: u32 = 1 << 31;
+
+/// Single-producer single-consumer ring of pulses, safe to push from an ISR.
+///
+/// Slots are atomics rather than plain memory, so sharing one between the
+/// interrupt and the app needs no unsafe. Holds up to `N - 1` pulses; when
+/// full, new pulses are dropped and the overflow is remembered instead.
+pub struct PulseRing<const N: usize> {
+    slots: [AtomicU32; N],
+    /// Next slot to write — only the producer stores it.
+    head: AtomicUsize,
+    /// Next slot to read — only the consumer stores it.
+    tail: AtomicUsize,
+    overflowed: AtomicBool,
+}
+
+impl<const N: usize> PulseRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { AtomicU32::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
+        }
+    }
+
+    /// Producer side: queue a pulse. Returns false (and flags an overflow) if full.
+    ///
+    /// Durations too long for 31 bits are clamped — nothing that long is a symbol.
+    pub fn push(&self, pulse: LevelDuration) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == self.tail.load(Ordering::Acquire) {
+            self.overflowed.store(true, Ordering::Relaxed);
+            return false;
+        }
+
+        let level = if pulse.level { LEVEL_BIT } else { 0 };
+        self.slots[head].store(level | pulse.duration.min(LEVEL_BIT - 1), Ordering::Relaxed);
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    /// Consumer side: take the oldest pulse, if any.
+    pub fn pop(&self) -> Option<LevelDuration> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let slot = self.slots[tail].load(Ordering::Relaxed);
+        self.tail.store((tail + 1) % N, Ordering::Release);
+        Some(LevelDuration {
+            level: slot & LEVEL_BIT != 0,
+            duration: slot & !LEVEL_BIT,
+        })
+    }
+
+    /// Consumer side: whether pulses were dropped since the last call.
+    pub fn take_overflow(&self) -> bool {
+        self.overflowed.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize> Default for PulseRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pulse(level: bool, duration: u32) -> LevelDuration {
+        LevelDuration { level, duration }
+    }
+
+    #[test]
+    fn test_push_pop_in_order() {
+        let ring = PulseRing::<8>::new();
+        assert_eq!(ring.pop(), None);
+
+        assert!(ring.push(pulse(true, 604)));
+        assert!(ring.push(pulse(false, 1208)));
+        assert_eq!(ring.pop(), Some(pulse(true, 604)));
+        assert_eq!(ring.pop(), Some(pulse(false, 1208)));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_wraps_around() {
+        let ring = PulseRing::<4>::new();
+        for i in 0..20 {
+            assert!(ring.push(pulse(i % 2 == 0, i)));
+            assert!(ring.push(pulse(i % 2 != 0, i + 100)));
+            assert_eq!(ring.pop(), Some(pulse(i % 2 == 0, i)));
+            assert_eq!(ring.pop(), Some(pulse(i % 2 != 0, i + 100)));
+        }
+        assert!(!ring.take_overflow());
+    }
+
+    #[test]
+    fn test_overflow_drops_newest() {
+        let ring = PulseRing::<4>::new();
+        assert!(ring.push(pulse(true, 1)));
+        assert!(ring.push(pulse(false, 2)));
+        assert!(ring.push(pulse(true, 3)));
+        assert!(!ring.push(pulse(false, 4)));
+
+        assert!(ring.take_overflow());
+        assert!(!ring.take_overflow());
+        assert_eq!(ring.pop(), Some(pulse(true, 1)));
+        assert_eq!(ring.pop(), Some(pulse(false, 2)));
+        assert_eq!(ring.pop(), Some(pulse(true, 3)));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn test_clamps_long_durations() {
+        let ring = PulseRing::<4>::new();
+        ring.push(pulse(false, u32::MAX));
+        assert_eq!(ring.pop(), Some(pulse(false, LEVEL_BIT - 1)));
+    }
+
+    #[test]
+    fn test_concurrent_producer() {
+        extern crate std;
+
+        // Stand-in for the ISR: a thread pushing while the app drains
+        let ring = PulseRing::<64>::new();
+        let count = 10_000u32;
+        let mut received = 0;
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..count {
+                    while !ring.push(pulse(i % 2 == 0, i)) {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            while received < count {
+                if let Some(p) = ring.pop() {
+                    assert_eq!(p, pulse(received % 2 == 0, received));
+                    received += 1;
+                }
+            }
+        });
+        ring.take_overflow();
+        assert_eq!(ring.pop(), None);
+    }
+}
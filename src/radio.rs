@@ -0,0 +1,1251 @@
+//! Radio backends and the command path above them — pure Rust, no unsafe.
+//!
+//! `RadioBackend` is the handful of things we ever ask of a Sub-GHz radio.
+//! The CC1101 implementation lives in `subghz.rs`; tests use a recording
+//! backend that keeps every waveform it was asked to send. The radio purrs,
+//! the tests listen :3
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::blind::SomfyBlind;
+use crate::protocol::{
+    LevelDuration, Press, SomfyCommand, SomfyFrame, SomfyTimings, TransmissionEncoder, decode_all,
+};
+
+/// Somfy RTS frequency: 433.42 MHz
+pub const SOMFY_FREQUENCY_HZ: u32 = 433_420_000;
+
+/// Bands the CC1101 can tune to, in Hz (inclusive), as the firmware checks them.
+/// Whether the region lock lets us transmit there is the radio's call.
+const CC1101_BANDS: [(u32, u32); 3] = [
+    (299_999_755, 348_000_335),
+    (386_999_938, 464_000_000),
+    (778_999_847, 928_000_000),
+];
+
+/// Whether the CC1101 can tune to `hz` at all.
+pub fn is_valid_frequency(hz: u32) -> bool {
+    CC1101_BANDS.iter().any(|&(low, high)| (low..=high).contains(&hz))
+}
+
+/// Which CC1101 the app talks through.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum RadioDevice {
+    /// The Flipper's own radio.
+    #[default]
+    Internal,
+    /// A CC1101 board on the GPIO header, for blinds out of reach of the
+    /// internal antenna. Falls back to `Internal` when not plugged in.
+    External,
+}
+
+impl RadioDevice {
+    /// Parse the settings file spelling: `internal` or `external`.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "internal" => Some(RadioDevice::Internal),
+            "external" => Some(RadioDevice::External),
+            _ => None,
+        }
+    }
+
+    /// The settings file spelling, the inverse of `parse`.
+    pub fn encode(self) -> &'static str {
+        match self {
+            RadioDevice::Internal => "internal",
+            RadioDevice::External => "external",
+        }
+    }
+}
+
+/// Modem settings a backend can be asked to load.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Preset {
+    /// OOK, 270 kHz bandwidth, asynchronous — a narrower filter for clones
+    /// that sit right on their frequency.
+    Ook270Async,
+    /// OOK, 650 kHz bandwidth, asynchronous — what Somfy remotes sound like.
+    #[default]
+    Ook650Async,
+}
+
+impl Preset {
+    /// The stock firmware's name, as used in `.sub` files and the state file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Ook270Async => "FuriHalSubGhzPresetOok270Async",
+            Preset::Ook650Async => "FuriHalSubGhzPresetOok650Async",
+        }
+    }
+
+    /// Parse a stock preset name, the inverse of `name`.
+    pub fn parse(text: &str) -> Option<Self> {
+        [Preset::Ook270Async, Preset::Ook650Async]
+            .into_iter()
+            .find(|preset| preset.name() == text)
+    }
+}
+
+/// A stream of pulses for the radio to pull from as it transmits.
+///
+/// Pulled from interrupt context, so it must be cheap and never block.
+pub type PulseSource = Box<dyn Iterator<Item = LevelDuration> + Send>;
+
+/// The operations the app needs from a Sub-GHz radio.
+///
+/// A session is `begin`, configuration, any number of transmissions
+/// (`start_tx`, polling `is_tx_complete`, `stop_tx`) or `rx_stream` calls,
+/// then `end`.
+pub trait RadioBackend {
+    /// Claim and power up the radio. False if there is no radio to claim.
+    fn begin(&mut self) -> bool;
+
+    /// The radio the last `begin` claimed.
+    fn device(&self) -> RadioDevice {
+        RadioDevice::Internal
+    }
+
+    /// Whether the last `begin` had to settle for a different radio than asked for.
+    fn fell_back(&self) -> bool {
+        false
+    }
+
+    /// Power down and release the radio.
+    fn end(&mut self);
+
+    /// Tune to `hz`. False if the radio can't (or isn't allowed to) use it.
+    fn set_frequency(&mut self, hz: u32) -> bool;
+
+    /// Load modem settings.
+    fn load_preset(&mut self, preset: Preset);
+
+    /// Start transmitting `pulses` in the background and return straight away.
+    fn start_tx(&mut self, pulses: PulseSource) -> Result<(), TxError>;
+
+    /// Whether every pulse handed to `start_tx` has gone out.
+    fn is_tx_complete(&mut self) -> bool;
+
+    /// End the transmission — cutting it off if it's still going — and go idle.
+    fn stop_tx(&mut self);
+
+    /// Listen-before-talk settings; off unless the radio says otherwise.
+    fn listen_before_talk(&self) -> ListenBeforeTalk {
+        ListenBeforeTalk::default()
+    }
+
+    /// Signal strength on the tuned frequency in dBm, listening first if need be.
+    fn rssi(&mut self) -> f32;
+
+    /// Receive for up to `timeout_ms`, feeding every captured pulse to `sink`
+    /// until it breaks. False if reception could not start.
+    fn rx_stream(
+        &mut self,
+        timeout_ms: u32,
+        sink: &mut dyn FnMut(LevelDuration) -> ControlFlow<()>,
+    ) -> bool;
+}
+
+/// Slack on top of a transmission's air time before it's declared hung.
+const TX_TIMEOUT_MARGIN_MS: u32 = 1000;
+
+/// Longest a held button keeps transmitting — a remote stuck under a cushion
+/// shouldn't hog the band forever.
+const MAX_HOLD_MS: u32 = 30_000;
+
+/// Listen-before-talk: hold a transmission back while someone else is on the
+/// channel, so presses from several people at once don't trample each other.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ListenBeforeTalk {
+    pub enabled: bool,
+    /// The channel counts as busy at or above this signal strength.
+    pub threshold_dbm: i32,
+    /// Give up if the channel hasn't cleared after this long.
+    pub max_wait_ms: u32,
+}
+
+impl Default for ListenBeforeTalk {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_dbm: -75,
+            max_wait_ms: 2000,
+        }
+    }
+}
+
+/// Shortest pause before listening again on a busy channel.
+const LBT_BACKOFF_MIN_MS: u32 = 20;
+
+/// Longest pause — about one Somfy frame, so a repeat's gap isn't missed.
+const LBT_BACKOFF_MAX_MS: u32 = 150;
+
+/// What the channel said when asked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    /// Quiet — go ahead.
+    Clear,
+    /// Busy, or not time to listen again yet.
+    Wait,
+    /// Still busy after the longest wait allowed.
+    Busy,
+}
+
+/// Random back-off for listen-before-talk.
+///
+/// Each busy sample pauses for a random while, so two Flippers that started
+/// listening together don't start talking together too.
+pub struct BackOff {
+    config: ListenBeforeTalk,
+    started_ms: u32,
+    /// When to listen next, in ms after `started_ms`.
+    next_sample_ms: u32,
+    /// xorshift32 state — never zero.
+    rng: u32,
+}
+
+impl BackOff {
+    /// Start listening at `now_ms`. `seed` picks the jitter sequence.
+    pub fn new(config: ListenBeforeTalk, now_ms: u32, seed: u32) -> Self {
+        Self {
+            config,
+            started_ms: now_ms,
+            next_sample_ms: 0,
+            rng: seed.max(1),
+        }
+    }
+
+    /// Check the channel at `now_ms`, sampling `rssi` only when it's time to listen.
+    pub fn poll(&mut self, now_ms: u32, rssi: impl FnOnce() -> f32) -> Channel {
+        let elapsed_ms = now_ms.wrapping_sub(self.started_ms);
+        if elapsed_ms < self.next_sample_ms {
+            return Channel::Wait;
+        }
+        if rssi() < self.config.threshold_dbm as f32 {
+            return Channel::Clear;
+        }
+        if elapsed_ms >= self.config.max_wait_ms {
+            return Channel::Busy;
+        }
+        self.next_sample_ms = elapsed_ms + self.jitter_ms();
+        Channel::Wait
+    }
+
+    fn jitter_ms(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        LBT_BACKOFF_MIN_MS + self.rng % (LBT_BACKOFF_MAX_MS - LBT_BACKOFF_MIN_MS + 1)
+    }
+}
+
+/// Why a transmission didn't go out, or didn't go out in full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxError {
+    /// There was no radio to claim.
+    DeviceNotFound,
+    /// The radio can't, or isn't allowed to, use this frequency (in Hz).
+    RegionLocked(u32),
+    /// The radio wouldn't switch to transmit.
+    SetTxRefused,
+    /// The radio wouldn't start streaming pulses.
+    AsyncStartFailed,
+    /// The radio never reported completion and was cut off.
+    Timeout,
+    /// Cancelled part way — the frame on air was finished first.
+    Cancelled,
+    /// Cancelled while listen-before-talk waited, before anything went out.
+    CancelledWaiting,
+    /// The press came to no frames at all.
+    NothingToSend,
+    /// Listen-before-talk never found the channel quiet.
+    ChannelBusy,
+    /// The rolling code couldn't be reserved on the SD card, so nothing was sent.
+    ReserveFailed,
+}
+
+impl TxError {
+    /// Whether some of the transmission reached the air, using up its rolling code.
+    pub fn used_code(self) -> bool {
+        matches!(self, TxError::Timeout | TxError::Cancelled)
+    }
+
+    /// A short description, for the screen.
+    pub fn message(self) -> &'static str {
+        match self {
+            TxError::DeviceNotFound => "No radio found",
+            TxError::RegionLocked(_) => "Frequency not allowed",
+            TxError::SetTxRefused => "Radio refused TX",
+            TxError::AsyncStartFailed => "TX failed to start",
+            TxError::Timeout => "TX timed out",
+            TxError::Cancelled => "Cancelled",
+            TxError::CancelledWaiting => "Cancelled before sending",
+            TxError::NothingToSend => "Nothing to send",
+            TxError::ChannelBusy => "Channel busy",
+            TxError::ReserveFailed => "Can't save rolling code",
+        }
+    }
+
+    /// A stable one-word name, for the TX log.
+    pub fn code(self) -> &'static str {
+        match self {
+            TxError::DeviceNotFound => "device_not_found",
+            TxError::RegionLocked(_) => "region_locked",
+            TxError::SetTxRefused => "set_tx_refused",
+            TxError::AsyncStartFailed => "async_start_failed",
+            TxError::Timeout => "timeout",
+            TxError::Cancelled => "cancelled",
+            TxError::CancelledWaiting => "cancelled_waiting",
+            TxError::NothingToSend => "nothing_to_send",
+            TxError::ChannelBusy => "channel_busy",
+            TxError::ReserveFailed => "reserve_failed",
+        }
+    }
+}
+
+/// Whether a transmission with this result used up its rolling code.
+pub fn code_used(result: Result<(), TxError>) -> bool {
+    result.map_or_else(TxError::used_code, |()| true)
+}
+
+/// Lets the UI cut a transmission short; shared with the pulse source.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An encoder that winds down once its token is cancelled, finishing the
+/// frame on air so the motor never hears half a frame.
+struct CancellableEncoder {
+    encoder: TransmissionEncoder,
+    cancel: CancelToken,
+    /// Frames to send even if cancelled sooner.
+    min_frames: u32,
+    stopping: bool,
+}
+
+impl Iterator for CancellableEncoder {
+    type Item = LevelDuration;
+
+    fn next(&mut self) -> Option<LevelDuration> {
+        if !self.stopping && self.cancel.is_cancelled() {
+            self.encoder.stop_after(self.min_frames);
+            self.stopping = true;
+        }
+        self.encoder.next()
+    }
+}
+
+/// Everything needed to put a frame on air.
+pub struct TxRequest {
+    pub frame: SomfyFrame,
+    pub timings: SomfyTimings,
+    pub press: Press,
+    pub frequency: u32,
+    pub preset: Preset,
+}
+
+/// Called once with the result when a transmission ends.
+type Completion<'a> = Box<dyn FnOnce(Result<(), TxError>) + 'a>;
+
+/// A Somfy RTS transmission running in the background.
+///
+/// The radio pulls pulses from interrupt context while the app keeps its UI
+/// going; call `poll` regularly until it returns true. However it ends —
+/// sent, cancelled, timed out or failed to start — the radio is released and
+/// `on_complete` runs exactly once with the result. Dropping an unfinished
+/// transmission cuts it off.
+pub struct Transmission<'a, R: RadioBackend> {
+    radio: &'a mut R,
+    cancel: CancelToken,
+    /// Whether this is a `Press::Held`, ended by `cancel` as a matter of course.
+    held: bool,
+    started_ms: u32,
+    timeout_ms: u32,
+    /// Whether `begin` succeeded, so `end` is owed.
+    session: bool,
+    /// Set while listen-before-talk waits for a quiet channel.
+    listening: Option<BackOff>,
+    /// The pulses to send once the channel is clear.
+    pending: Option<PulseSource>,
+    /// Taken when the transmission finishes.
+    on_complete: Option<Completion<'a>>,
+}
+
+impl<'a, R: RadioBackend> Transmission<'a, R> {
+    /// Start sending `frame` as a `press` at `now_ms` (any millisecond clock).
+    ///
+    /// A hold keeps repeating the frame (with repeat syncs) until the hold time
+    /// is covered, or for `Press::Held` until `cancel` (the button's release).
+    /// Pulse lengths come from `timings`. Frequencies the CC1101 can't tune to
+    /// never reach the radio and fail straight away. If the radio has
+    /// listen-before-talk on, nothing goes out until the channel is quiet.
+    pub fn start(
+        radio: &'a mut R,
+        request: TxRequest,
+        now_ms: u32,
+        on_complete: impl FnOnce(Result<(), TxError>) + 'a,
+    ) -> Self {
+        let TxRequest { frame, timings, press, frequency, preset } = request;
+        let length = frame.length();
+        let frames = press.frame_count(length, &timings);
+        let held = matches!(press, Press::Held(_));
+        let airtime_ms = (length.airtime_us(&timings, frames) / 1000).min(u32::MAX as u64);
+        let max_ms = if held { MAX_HOLD_MS } else { 0 };
+
+        let cancel = CancelToken::default();
+        let mut tx = Self {
+            radio,
+            cancel: cancel.clone(),
+            held,
+            started_ms: now_ms,
+            timeout_ms: (airtime_ms as u32)
+                .saturating_add(max_ms)
+                .saturating_add(TX_TIMEOUT_MARGIN_MS),
+            session: false,
+            listening: None,
+            pending: None,
+            on_complete: Some(Box::new(on_complete)),
+        };
+
+        if frames == 0 {
+            tx.finish(Err(TxError::NothingToSend));
+            return tx;
+        }
+        if !is_valid_frequency(frequency) {
+            tx.finish(Err(TxError::RegionLocked(frequency)));
+            return tx;
+        }
+        if !tx.radio.begin() {
+            tx.finish(Err(TxError::DeviceNotFound));
+            return tx;
+        }
+        tx.session = true;
+
+        tx.radio.load_preset(preset);
+        let pulses = CancellableEncoder {
+            encoder: TransmissionEncoder::new(&frame, &timings, (!held).then_some(frames)),
+            cancel,
+            min_frames: if held { frames } else { 0 },
+            stopping: false,
+        };
+        if !tx.radio.set_frequency(frequency) {
+            tx.finish(Err(TxError::RegionLocked(frequency)));
+            return tx;
+        }
+
+        let lbt = tx.radio.listen_before_talk();
+        if lbt.enabled {
+            let seed = now_ms ^ (frame.address().get() << 8) ^ frame.rolling_code() as u32;
+            tx.listening = Some(BackOff::new(lbt, now_ms, seed));
+            tx.pending = Some(Box::new(pulses));
+        } else {
+            tx.start_tx(Box::new(pulses));
+        }
+        tx
+    }
+
+    fn start_tx(&mut self, pulses: PulseSource) {
+        if let Err(error) = self.radio.start_tx(pulses) {
+            self.finish(Err(error));
+        }
+    }
+
+    /// Ask for the transmission to end after the frame on air.
+    ///
+    /// For `Press::Held` this is the release: the minimum frames still go out
+    /// and the press counts as sent.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Check on the radio at `now_ms`. True once the transmission is over.
+    pub fn poll(&mut self, now_ms: u32) -> bool {
+        if self.on_complete.is_none() {
+            return true;
+        }
+        if let Some(listening) = &mut self.listening {
+            let channel = listening.poll(now_ms, || self.radio.rssi());
+            // Nothing is on air yet, so a cancel (but not a hold's release)
+            // drops the frame and leaves its rolling code unused
+            if self.cancel.is_cancelled() && !self.held {
+                self.finish(Err(TxError::CancelledWaiting));
+                return true;
+            }
+            match channel {
+                Channel::Wait => return false,
+                Channel::Busy => {
+                    self.finish(Err(TxError::ChannelBusy));
+                    return true;
+                }
+                Channel::Clear => {
+                    // The timeout runs from when the radio starts talking
+                    self.listening = None;
+                    self.started_ms = now_ms;
+                    if let Some(pulses) = self.pending.take() {
+                        self.start_tx(pulses);
+                    }
+                    return self.on_complete.is_none();
+                }
+            }
+        }
+        let elapsed_ms = now_ms.wrapping_sub(self.started_ms);
+        if self.radio.is_tx_complete() {
+            let cancelled = self.cancel.is_cancelled() && !self.held;
+            self.finish(if cancelled { Err(TxError::Cancelled) } else { Ok(()) });
+        } else if elapsed_ms >= self.timeout_ms {
+            self.finish(Err(TxError::Timeout));
+        } else {
+            if self.held && elapsed_ms >= MAX_HOLD_MS {
+                self.cancel();
+            }
+            return false;
+        }
+        true
+    }
+
+    fn finish(&mut self, result: Result<(), TxError>) {
+        self.listening = None;
+        self.pending = None;
+        if self.session {
+            self.radio.stop_tx();
+            self.radio.end();
+            self.session = false;
+        }
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(result);
+        }
+    }
+}
+
+impl<R: RadioBackend> Drop for Transmission<'_, R> {
+    fn drop(&mut self) {
+        self.finish(Err(TxError::Cancelled));
+    }
+}
+
+/// Start sending `command` as `blind`'s remote.
+///
+/// The rolling code is used up if any of the transmission reached the air,
+/// before `on_complete` runs; a send that never started leaves it alone, so
+/// the next press retries it.
+pub fn start_send<'a, R: RadioBackend>(
+    radio: &'a mut R,
+    blind: &'a mut SomfyBlind,
+    command: SomfyCommand,
+    press: Press,
+    now_ms: u32,
+    on_complete: impl FnOnce(Result<(), TxError>) + 'a,
+) -> Transmission<'a, R> {
+    let request = TxRequest {
+        frame: blind.frame(command),
+        timings: blind.timings,
+        press,
+        frequency: blind.frequency,
+        preset: blind.preset,
+    };
+    Transmission::start(radio, request, now_ms, move |result| {
+        if code_used(result) {
+            blind.advance_rolling_code();
+        }
+        on_complete(result);
+    })
+}
+
+/// Why listening for a remote didn't happen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RxError {
+    /// There was no radio to claim.
+    DeviceNotFound,
+    /// The radio can't, or isn't allowed to, use this frequency (in Hz).
+    FrequencyRefused(u32),
+    /// The radio wouldn't start receiving.
+    StartFailed,
+}
+
+impl RxError {
+    /// A short description, for the screen.
+    pub fn message(self) -> &'static str {
+        match self {
+            RxError::DeviceNotFound => "No radio found",
+            RxError::FrequencyRefused(_) => "Frequency not allowed",
+            RxError::StartFailed => "RX failed to start",
+        }
+    }
+}
+
+/// Pulses decoded at a time while listening — a few frames' worth.
+const LISTEN_WINDOW: usize = 512;
+
+/// Pulses kept from one window to the next, so a frame the window's end cuts
+/// in two is still heard whole in the next.
+const LISTEN_OVERLAP: usize = 256;
+
+/// Listen on `frequency` for up to `timeout_ms` and decode the Somfy frames
+/// of the first press heard, e.g. to take over from a remote.
+///
+/// Captures are decoded a window at a time, so noise while nobody presses
+/// anything never piles up in memory. Empty if nothing decoded in time.
+pub fn listen(
+    radio: &mut impl RadioBackend,
+    frequency: u32,
+    preset: Preset,
+    timeout_ms: u32,
+) -> Result<Vec<SomfyFrame>, RxError> {
+    if !is_valid_frequency(frequency) {
+        return Err(RxError::FrequencyRefused(frequency));
+    }
+    if !radio.begin() {
+        return Err(RxError::DeviceNotFound);
+    }
+    radio.load_preset(preset);
+    if !radio.set_frequency(frequency) {
+        radio.end();
+        return Err(RxError::FrequencyRefused(frequency));
+    }
+
+    let mut pulses = Vec::new();
+    let mut frames = Vec::new();
+    let started = radio.rx_stream(timeout_ms, &mut |pulse| {
+        pulses.push(pulse);
+        if pulses.len() < LISTEN_WINDOW {
+            return ControlFlow::Continue(());
+        }
+        frames = decode_all(&pulses, &SomfyTimings::DEFAULT);
+        if !frames.is_empty() {
+            return ControlFlow::Break(());
+        }
+        pulses.drain(..LISTEN_WINDOW - LISTEN_OVERLAP);
+        ControlFlow::Continue(())
+    });
+    radio.end();
+
+    if !started {
+        return Err(RxError::StartFailed);
+    }
+    // Whatever came in after the last full window
+    if frames.is_empty() {
+        frames = decode_all(&pulses, &SomfyTimings::DEFAULT);
+    }
+    Ok(frames)
+}
+
+/// A radio for host tests: records everything instead of touching hardware.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingBackend {
+    /// Pretend there is no radio.
+    pub missing: bool,
+    /// Pretend every transmission is refused for some reason of the radio's own.
+    pub refuse_tx: bool,
+    /// Pretend the firmware's region lock forbids sending on any frequency.
+    pub region_locked: bool,
+    /// Pretend the radio won't tune to any frequency.
+    pub refuse_frequency: bool,
+    /// Pretend the radio never finishes transmitting.
+    pub hang_tx: bool,
+    /// Pulses sent per `is_tx_complete` call, standing in for air time.
+    /// `None` sends everything at once.
+    pub pulses_per_poll: Option<usize>,
+    /// Pulses handed out by `rx_stream`, in order.
+    pub rx_script: alloc::vec::Vec<LevelDuration>,
+    pub lbt: ListenBeforeTalk,
+    /// Signal strengths handed out by `rssi`, in order; the last one sticks.
+    pub rssi_script: alloc::vec::Vec<f32>,
+    /// How many times `rssi` was sampled.
+    pub rssi_samples: usize,
+    /// Whether a session is open.
+    pub active: bool,
+    pub frequency: Option<u32>,
+    pub preset: Option<Preset>,
+    /// The transmission in progress.
+    pub source: Option<PulseSource>,
+    /// Every waveform sent, one entry per `start_tx` call.
+    pub transmissions: alloc::vec::Vec<alloc::vec::Vec<LevelDuration>>,
+}
+
+#[cfg(test)]
+impl RadioBackend for RecordingBackend {
+    fn begin(&mut self) -> bool {
+        assert!(!self.active, "begin without end");
+        self.active = !self.missing;
+        self.active
+    }
+
+    fn end(&mut self) {
+        assert!(self.active, "end without begin");
+        assert!(self.source.is_none(), "end while transmitting");
+        self.active = false;
+    }
+
+    fn set_frequency(&mut self, hz: u32) -> bool {
+        assert!(self.active);
+        if self.refuse_frequency {
+            return false;
+        }
+        self.frequency = Some(hz);
+        true
+    }
+
+    fn load_preset(&mut self, preset: Preset) {
+        assert!(self.active);
+        self.preset = Some(preset);
+    }
+
+    fn listen_before_talk(&self) -> ListenBeforeTalk {
+        self.lbt
+    }
+
+    fn rssi(&mut self) -> f32 {
+        assert!(self.active);
+        let sample = self.rssi_script.get(self.rssi_samples).or(self.rssi_script.last());
+        self.rssi_samples += 1;
+        sample.copied().unwrap_or(-120.0)
+    }
+
+    fn start_tx(&mut self, pulses: PulseSource) -> Result<(), TxError> {
+        assert!(self.active);
+        assert!(self.source.is_none(), "start_tx while transmitting");
+        if self.region_locked {
+            return Err(TxError::RegionLocked(self.frequency.expect("not tuned")));
+        }
+        if self.refuse_tx {
+            return Err(TxError::SetTxRefused);
+        }
+        self.source = Some(pulses);
+        self.transmissions.push(alloc::vec::Vec::new());
+        Ok(())
+    }
+
+    fn is_tx_complete(&mut self) -> bool {
+        let source = self.source.as_mut().expect("no transmission");
+        if self.hang_tx {
+            return false;
+        }
+        let sent = self.transmissions.last_mut().unwrap();
+        for _ in 0..self.pulses_per_poll.unwrap_or(usize::MAX) {
+            match source.next() {
+                Some(pulse) => sent.push(pulse),
+                None => return true,
+            }
+        }
+        false
+    }
+
+    fn stop_tx(&mut self) {
+        assert!(self.active);
+        self.source = None;
+    }
+
+    fn rx_stream(
+        &mut self,
+        _timeout_ms: u32,
+        sink: &mut dyn FnMut(LevelDuration) -> ControlFlow<()>,
+    ) -> bool {
+        assert!(self.active);
+        for &pulse in &self.rx_script {
+            if sink(pulse).is_break() {
+                break;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use heapless::String;
+
+    use super::*;
+    use crate::protocol::{FrameLength, KeyPolicy, SomfyAddress, build_transmission, decode_all};
+
+    fn blind() -> SomfyBlind {
+        let name = String::try_from("Office").unwrap();
+        let mut blind = SomfyBlind::new(name, SomfyAddress::new(0x123456).unwrap(), 1);
+        blind.key_policy = KeyPolicy::Fixed;
+        blind
+    }
+
+    /// Run a send to completion on a fake millisecond clock.
+    fn send(
+        radio: &mut RecordingBackend,
+        blind: &mut SomfyBlind,
+        command: SomfyCommand,
+        press: Press,
+    ) -> Result<(), TxError> {
+        let mut result = None;
+        let mut tx = start_send(radio, blind, command, press, 0, |r| result = Some(r));
+        let mut now = 0;
+        while !tx.poll(now) {
+            now += 10;
+        }
+        drop(tx);
+        result.expect("on_complete must run")
+    }
+
+    #[test]
+    fn test_valid_frequencies() {
+        for hz in [SOMFY_FREQUENCY_HZ, 433_920_000, 315_000_000, 868_350_000, 915_000_000] {
+            assert!(is_valid_frequency(hz), "{hz}");
+        }
+        for hz in [0, 299_999_754, 348_000_336, 386_999_937, 464_000_001, 928_000_001] {
+            assert!(!is_valid_frequency(hz), "{hz}");
+        }
+    }
+
+    #[test]
+    fn test_preset_names_round_trip() {
+        for preset in [Preset::Ook270Async, Preset::Ook650Async] {
+            assert_eq!(Preset::parse(preset.name()), Some(preset));
+        }
+        assert_eq!(Preset::parse("FuriHalSubGhzPreset2FSKDev238Async"), None);
+        assert_eq!(Preset::default(), Preset::Ook650Async);
+    }
+
+    #[test]
+    fn test_send_uses_blind_frequency_and_preset() {
+        let mut radio = RecordingBackend::default();
+        let mut b = blind();
+        b.frequency = 433_920_000;
+        b.preset = Preset::Ook270Async;
+
+        assert_eq!(send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(1)), Ok(()));
+        assert_eq!(radio.frequency, Some(433_920_000));
+        assert_eq!(radio.preset, Some(Preset::Ook270Async));
+    }
+
+    #[test]
+    fn test_send_rejects_invalid_frequency() {
+        let mut radio = RecordingBackend::default();
+        let mut b = blind();
+        b.frequency = 500_000_000;
+
+        let result = send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(1));
+        assert_eq!(result, Err(TxError::RegionLocked(500_000_000)));
+        assert!(radio.frequency.is_none(), "radio must not be touched");
+        assert_eq!(b.rolling_code, 1);
+    }
+
+    #[test]
+    fn test_radio_device_text_round_trip() {
+        for device in [RadioDevice::Internal, RadioDevice::External] {
+            assert_eq!(RadioDevice::parse(device.encode()), Some(device));
+        }
+        assert_eq!(RadioDevice::parse("cc1101_ext"), None);
+        assert_eq!(RadioDevice::default(), RadioDevice::Internal);
+    }
+
+    #[test]
+    fn test_send_command_records_waveform() {
+        let mut radio = RecordingBackend::default();
+        let mut b = blind();
+        let frame = b.frame(SomfyCommand::Up);
+
+        assert_eq!(send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(4)), Ok(()));
+        assert!(!radio.active);
+        assert_eq!(radio.frequency, Some(SOMFY_FREQUENCY_HZ));
+        assert_eq!(radio.preset, Some(Preset::Ook650Async));
+        assert_eq!(radio.transmissions, [build_transmission(&frame, &SomfyTimings::DEFAULT, 4)]);
+        assert_eq!(b.rolling_code, 2);
+    }
+
+    #[test]
+    fn test_consecutive_presses_roll_the_code() {
+        let mut radio = RecordingBackend::default();
+        let mut b = blind();
+        for command in [SomfyCommand::Up, SomfyCommand::Stop, SomfyCommand::Down] {
+            assert_eq!(send(&mut radio, &mut b, command, Press::Frames(1)), Ok(()));
+        }
+
+        let sent: Vec<_> = radio
+            .transmissions
+            .iter()
+            .flat_map(|pulses| decode_all(pulses, &SomfyTimings::DEFAULT))
+            .map(|frame| (frame.command(), frame.rolling_code()))
+            .collect();
+        assert_eq!(
+            sent,
+            [(SomfyCommand::Up, 1), (SomfyCommand::My, 2), (SomfyCommand::Down, 3)]
+        );
+    }
+
+    #[test]
+    fn test_failed_send_keeps_rolling_code() {
+        let mut b = blind();
+        let press = Press::Frames(4);
+
+        let mut missing = RecordingBackend { missing: true, ..Default::default() };
+        let result = send(&mut missing, &mut b, SomfyCommand::Up, press);
+        assert_eq!(result, Err(TxError::DeviceNotFound));
+        assert!(missing.transmissions.is_empty());
+
+        let mut refusing = RecordingBackend { refuse_tx: true, ..Default::default() };
+        let result = send(&mut refusing, &mut b, SomfyCommand::Up, press);
+        assert_eq!(result, Err(TxError::SetTxRefused));
+        assert!(!refusing.active, "radio must be released after a refused send");
+
+        assert_eq!(b.rolling_code, 1);
+    }
+
+    #[test]
+    fn test_frequency_refused_by_radio() {
+        let mut radio = RecordingBackend { refuse_frequency: true, ..Default::default() };
+        let mut b = blind();
+        b.frequency = 433_920_000;
+
+        let result = send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(4));
+        assert_eq!(result, Err(TxError::RegionLocked(433_920_000)));
+        assert!(radio.transmissions.is_empty());
+        assert!(!radio.active);
+        assert_eq!(b.rolling_code, 1);
+    }
+
+    #[test]
+    fn test_frequency_refused_by_region_lock() {
+        let mut radio = RecordingBackend { region_locked: true, ..Default::default() };
+        let mut b = blind();
+        b.frequency = 868_950_000;
+
+        let result = send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(4));
+        assert_eq!(result, Err(TxError::RegionLocked(868_950_000)));
+        assert!(radio.transmissions.is_empty());
+        assert!(!radio.active);
+        assert_eq!(b.rolling_code, 1);
+    }
+
+    #[test]
+    fn test_only_errors_after_going_on_air_use_the_code() {
+        assert!(code_used(Ok(())));
+        assert!(code_used(Err(TxError::Cancelled)));
+        assert!(code_used(Err(TxError::Timeout)));
+        for error in [
+            TxError::DeviceNotFound,
+            TxError::RegionLocked(SOMFY_FREQUENCY_HZ),
+            TxError::SetTxRefused,
+            TxError::AsyncStartFailed,
+            TxError::NothingToSend,
+            TxError::ChannelBusy,
+            TxError::CancelledWaiting,
+        ] {
+            assert!(!code_used(Err(error)), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn test_empty_press_sends_nothing() {
+        let mut radio = RecordingBackend::default();
+        let mut b = blind();
+        let result = send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(0));
+        assert_eq!(result, Err(TxError::NothingToSend));
+        assert!(radio.transmissions.is_empty());
+        assert_eq!(b.rolling_code, 1);
+    }
+
+    #[test]
+    fn test_cancel_finishes_frame_on_air() {
+        let mut radio = RecordingBackend { pulses_per_poll: Some(8), ..Default::default() };
+        let mut b = blind();
+        let mut result = None;
+        let press = Press::HoldMs(5000);
+        let mut tx = start_send(&mut radio, &mut b, SomfyCommand::Prog, press, 0, |r| {
+            result = Some(r)
+        });
+
+        assert!(!tx.poll(10));
+        tx.cancel();
+        let mut now = 10;
+        while !tx.poll(now) {
+            now += 10;
+        }
+        drop(tx);
+
+        assert_eq!(result, Some(Err(TxError::Cancelled)));
+        assert!(!radio.active && radio.source.is_none());
+        // Only whole frames went out, and far fewer than the hold asked for
+        let heard = decode_all(&radio.transmissions[0], &SomfyTimings::DEFAULT);
+        assert_eq!(heard.len(), 1);
+        assert_eq!(b.rolling_code, 2, "a cancelled send still used its code");
+    }
+
+    #[test]
+    fn test_hung_radio_times_out() {
+        let mut radio = RecordingBackend { hang_tx: true, ..Default::default() };
+        let mut b = blind();
+        let mut result = None;
+        let press = Press::Frames(4);
+        let mut tx = start_send(&mut radio, &mut b, SomfyCommand::Up, press, 1000, |r| {
+            result = Some(r)
+        });
+
+        let airtime_ms = FrameLength::Standard.airtime_us(&SomfyTimings::DEFAULT, 4) / 1000;
+        let deadline = 1000 + airtime_ms as u32 + TX_TIMEOUT_MARGIN_MS;
+        assert!(!tx.poll(deadline - 1));
+        assert!(tx.poll(deadline));
+        assert!(tx.poll(deadline + 10), "stays finished");
+        drop(tx);
+
+        assert_eq!(result, Some(Err(TxError::Timeout)));
+        assert!(!radio.active, "radio must be released after a timeout");
+    }
+
+    #[test]
+    fn test_timeout_survives_clock_wrap() {
+        let mut radio = RecordingBackend { hang_tx: true, ..Default::default() };
+        let request = TxRequest {
+            frame: blind().frame(SomfyCommand::Up),
+            timings: SomfyTimings::DEFAULT,
+            press: Press::Frames(1),
+            frequency: SOMFY_FREQUENCY_HZ,
+            preset: Preset::Ook650Async,
+        };
+        let mut tx = Transmission::start(&mut radio, request, u32::MAX - 5, |_| {});
+        assert!(!tx.poll(10));
+        assert!(tx.poll(TX_TIMEOUT_MARGIN_MS + 1000));
+    }
+
+    #[test]
+    fn test_dropping_transmission_releases_radio() {
+        let mut radio = RecordingBackend { pulses_per_poll: Some(1), ..Default::default() };
+        let mut b = blind();
+        let mut result = None;
+        let press = Press::Frames(4);
+        let tx = start_send(&mut radio, &mut b, SomfyCommand::Up, press, 0, |r| result = Some(r));
+        drop(tx);
+        assert_eq!(result, Some(Err(TxError::Cancelled)));
+        assert!(!radio.active);
+    }
+
+    /// Start a held press and keep polling until `release_at` ms, then release.
+    fn hold(
+        radio: &mut RecordingBackend,
+        b: &mut SomfyBlind,
+        release_at: u32,
+    ) -> (Result<(), TxError>, u32) {
+        let mut result = None;
+        let mut tx = start_send(radio, b, SomfyCommand::Down, Press::Held(4), 0, |r| {
+            result = Some(r)
+        });
+        let mut now = 0;
+        while !tx.poll(now) {
+            now += 10;
+            if now == release_at {
+                tx.cancel();
+            }
+        }
+        drop(tx);
+        (result.unwrap(), now)
+    }
+
+    #[test]
+    fn test_held_press_repeats_until_release() {
+        let mut radio = RecordingBackend { pulses_per_poll: Some(50), ..Default::default() };
+        let mut b = blind();
+        assert_eq!(hold(&mut radio, &mut b, 300).0, Ok(()));
+
+        let sent = &radio.transmissions[0];
+        let airtime: u64 = sent.iter().map(|t| t.duration as u64).sum();
+        assert!(airtime > FrameLength::Standard.airtime_us(&SomfyTimings::DEFAULT, 10));
+        // One press, one code, however long it was held
+        let heard = decode_all(sent, &SomfyTimings::DEFAULT);
+        assert_eq!(heard.len(), 1);
+        assert_eq!(heard[0].rolling_code(), 1);
+        assert_eq!(b.rolling_code, 2);
+    }
+
+    #[test]
+    fn test_quick_tap_sends_minimum_frames() {
+        let mut radio = RecordingBackend { pulses_per_poll: Some(50), ..Default::default() };
+        let mut b = blind();
+        assert_eq!(hold(&mut radio, &mut b, 10).0, Ok(()));
+
+        let frame = blind().frame(SomfyCommand::Down);
+        assert_eq!(radio.transmissions, [build_transmission(&frame, &SomfyTimings::DEFAULT, 4)]);
+    }
+
+    #[test]
+    fn test_stuck_hold_gives_up() {
+        let mut radio = RecordingBackend { pulses_per_poll: Some(50), ..Default::default() };
+        let mut b = blind();
+        let (result, ended) = hold(&mut radio, &mut b, u32::MAX);
+        assert_eq!(result, Ok(()), "winds down instead of timing out");
+        assert!(ended >= MAX_HOLD_MS);
+        assert_eq!(b.rolling_code, 2);
+    }
+
+    const LBT: ListenBeforeTalk = ListenBeforeTalk {
+        enabled: true,
+        threshold_dbm: -75,
+        max_wait_ms: 1000,
+    };
+
+    #[test]
+    fn test_backoff_jitter_stays_in_range() {
+        let mut backoff = BackOff::new(LBT, 0, 0x1234);
+        let waits: Vec<u32> = (0..200).map(|_| backoff.jitter_ms()).collect();
+        assert!(waits.iter().all(|w| (LBT_BACKOFF_MIN_MS..=LBT_BACKOFF_MAX_MS).contains(w)));
+        assert!(waits.iter().any(|&w| w != waits[0]), "jitter must vary");
+
+        // Different seeds, different rhythms
+        let mut other = BackOff::new(LBT, 0, 0x4321);
+        let other_waits: Vec<u32> = (0..200).map(|_| other.jitter_ms()).collect();
+        assert_ne!(waits, other_waits);
+    }
+
+    #[test]
+    fn test_backoff_only_samples_when_due() {
+        let mut backoff = BackOff::new(LBT, 500, 7);
+        assert_eq!(backoff.poll(500, || -40.0), Channel::Wait);
+        let next = 500 + backoff.next_sample_ms;
+        assert_eq!(backoff.poll(next - 1, || unreachable!("sampled too early")), Channel::Wait);
+        assert_eq!(backoff.poll(next, || -90.0), Channel::Clear);
+    }
+
+    #[test]
+    fn test_lbt_waits_for_quiet_channel() {
+        let mut radio = RecordingBackend {
+            lbt: LBT,
+            rssi_script: alloc::vec![-50.0, -60.0, -75.0, -95.0],
+            ..Default::default()
+        };
+        let mut b = blind();
+        assert_eq!(send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(4)), Ok(()));
+        assert_eq!(radio.rssi_samples, 4, "-75 dBm is still busy");
+        assert_eq!(radio.transmissions.len(), 1);
+        assert_eq!(b.rolling_code, 2);
+    }
+
+    #[test]
+    fn test_lbt_gives_up_on_busy_channel() {
+        let mut radio = RecordingBackend {
+            lbt: LBT,
+            rssi_script: alloc::vec![-40.0],
+            ..Default::default()
+        };
+        let mut b = blind();
+        let mut result = None;
+        let press = Press::Frames(4);
+        let mut tx = start_send(&mut radio, &mut b, SomfyCommand::Up, press, 0, |r| {
+            result = Some(r)
+        });
+        let mut now = 0;
+        while !tx.poll(now) {
+            now += 10;
+        }
+        drop(tx);
+
+        assert_eq!(result, Some(Err(TxError::ChannelBusy)));
+        assert!((LBT.max_wait_ms..LBT.max_wait_ms + LBT_BACKOFF_MAX_MS + 10).contains(&now));
+        assert!(radio.transmissions.is_empty());
+        assert!(!radio.active);
+        assert_eq!(b.rolling_code, 1, "nothing went out, the code is still fresh");
+    }
+
+    #[test]
+    fn test_cancel_while_listening_sends_nothing() {
+        let mut radio = RecordingBackend {
+            lbt: LBT,
+            rssi_script: alloc::vec![-40.0, -40.0, -40.0, -95.0],
+            ..Default::default()
+        };
+        let mut b = blind();
+        let mut result = None;
+        let press = Press::Frames(4);
+        let mut tx = start_send(&mut radio, &mut b, SomfyCommand::Up, press, 0, |r| {
+            result = Some(r)
+        });
+        let mut now = 0;
+        assert!(!tx.poll(now), "channel still busy");
+        tx.cancel();
+        while !tx.poll(now) {
+            now += 10;
+        }
+        drop(tx);
+
+        assert_eq!(result, Some(Err(TxError::CancelledWaiting)));
+        assert!(radio.transmissions.is_empty());
+        assert!(!radio.active);
+        assert_eq!(b.rolling_code, 1, "nothing went out, the code is still fresh");
+    }
+
+    #[test]
+    fn test_lbt_off_never_listens() {
+        let mut radio = RecordingBackend {
+            lbt: ListenBeforeTalk { enabled: false, ..LBT },
+            rssi_script: alloc::vec![-40.0],
+            ..Default::default()
+        };
+        let mut b = blind();
+        assert_eq!(send(&mut radio, &mut b, SomfyCommand::Up, Press::Frames(4)), Ok(()));
+        assert_eq!(radio.rssi_samples, 0);
+    }
+
+    #[test]
+    fn test_rx_stream_stops_when_sink_breaks() {
+        let frame = blind().frame(SomfyCommand::Prog);
+        let mut radio = RecordingBackend {
+            rx_script: build_transmission(&frame, &SomfyTimings::DEFAULT, 2),
+            ..Default::default()
+        };
+
+        let mut heard = Vec::new();
+        assert!(radio.begin());
+        radio.rx_stream(1000, &mut |pulse| {
+            heard.push(pulse);
+            if heard.len() == 10 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        radio.end();
+        assert_eq!(heard, radio.rx_script[..10]);
+    }
+
+    #[test]
+    fn test_listen_hears_a_press_after_noise() {
+        let frame = blind().frame(SomfyCommand::Down);
+        let noise = LevelDuration { level: true, duration: 90 };
+        let mut rx_script = alloc::vec![noise; 3 * LISTEN_WINDOW + 100];
+        rx_script.extend(build_transmission(&frame, &SomfyTimings::DEFAULT, 4));
+        let mut radio = RecordingBackend { rx_script, ..Default::default() };
+
+        let heard = listen(&mut radio, SOMFY_FREQUENCY_HZ, Preset::Ook650Async, 5000);
+        assert_eq!(heard, Ok(alloc::vec![frame]));
+        assert_eq!(radio.frequency, Some(SOMFY_FREQUENCY_HZ));
+        assert!(!radio.active);
+    }
+
+    #[test]
+    fn test_listen_hears_nothing() {
+        let mut radio = RecordingBackend::default();
+        assert_eq!(listen(&mut radio, SOMFY_FREQUENCY_HZ, Preset::Ook650Async, 10), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_listen_on_a_refused_frequency() {
+        let mut radio = RecordingBackend { refuse_frequency: true, ..Default::default() };
+        let heard = listen(&mut radio, SOMFY_FREQUENCY_HZ, Preset::Ook650Async, 5000);
+        assert_eq!(heard, Err(RxError::FrequencyRefused(SOMFY_FREQUENCY_HZ)));
+        assert!(!radio.active);
+
+        let heard = listen(&mut radio, 1_000_000, Preset::Ook650Async, 5000);
+        assert_eq!(heard, Err(RxError::FrequencyRefused(1_000_000)));
+        let mut radio = RecordingBackend { missing: true, ..Default::default() };
+        let heard = listen(&mut radio, SOMFY_FREQUENCY_HZ, Preset::Ook650Async, 5000);
+        assert_eq!(heard, Err(RxError::DeviceNotFound));
+    }
+}
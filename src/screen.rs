@@ -0,0 +1,172 @@
+//! A bare full-screen view: a header, some text, and the keys pressed on it.
+//!
+//! Dialogs block until a button is picked; this one doesn't, so the app can
+//! keep the radio going while it's shown and still hear the Back button.
+//! All the ViewPort plumbing stays in here. Whiskers twitching, ears up :3
+
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use core::ffi::c_void;
+
+use flipperzero::furi::message_queue::MessageQueue;
+use flipperzero::furi::sync::Mutex;
+use flipperzero::furi::time::FuriDuration;
+use flipperzero::gui::Gui;
+use flipperzero_sys as sys;
+
+/// Key events buffered between the GUI thread and the app.
+const EVENT_QUEUE_LEN: usize = 8;
+
+/// A button on the Flipper's d-pad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Ok,
+    Back,
+}
+
+/// A button going down or coming back up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: Key,
+    /// True on press, false on release.
+    pub pressed: bool,
+}
+
+/// What the screen shows.
+struct Content {
+    header: CString,
+    text: CString,
+}
+
+/// State shared with the GUI thread's callbacks.
+struct Shared {
+    content: Mutex<Content>,
+    events: MessageQueue<KeyEvent>,
+}
+
+/// Draw callback invoked by the GUI thread.
+unsafe extern "C" fn draw_callback(canvas: *mut sys::Canvas, context: *mut c_void) {
+    let shared = unsafe { &*(context as *const Shared) };
+    let content = shared.content.lock();
+    unsafe {
+        sys::canvas_clear(canvas);
+        sys::canvas_set_font(canvas, sys::FontPrimary);
+        sys::canvas_draw_str_aligned(
+            canvas,
+            0,
+            0,
+            sys::AlignLeft,
+            sys::AlignTop,
+            content.header.as_ptr(),
+        );
+        sys::canvas_set_font(canvas, sys::FontSecondary);
+        sys::elements_multiline_text_aligned(
+            canvas,
+            0,
+            26,
+            sys::AlignLeft,
+            sys::AlignTop,
+            content.text.as_ptr(),
+        );
+    }
+}
+
+/// Input callback invoked by the GUI thread.
+///
+/// Only presses and releases are passed on; short/long/repeat are derived
+/// events the app works out for itself where it cares.
+unsafe extern "C" fn input_callback(event: *mut sys::InputEvent, context: *mut c_void) {
+    let shared = unsafe { &*(context as *const Shared) };
+    let event = unsafe { &*event };
+
+    let key = match event.key {
+        sys::InputKeyUp => Key::Up,
+        sys::InputKeyDown => Key::Down,
+        sys::InputKeyLeft => Key::Left,
+        sys::InputKeyRight => Key::Right,
+        sys::InputKeyOk => Key::Ok,
+        sys::InputKeyBack => Key::Back,
+        _ => return,
+    };
+    let pressed = match event.type_ {
+        sys::InputTypePress => true,
+        sys::InputTypeRelease => false,
+        _ => return,
+    };
+    // Never block the GUI thread; if the app has fallen behind, drop the event
+    let _ = shared.events.put(KeyEvent { key, pressed }, FuriDuration::ZERO);
+}
+
+/// Turn text into a C string, dropping anything after an interior NUL.
+fn c_string(text: &str) -> CString {
+    let text = text.split('\0').next().unwrap_or_default();
+    CString::new(text).unwrap_or_default()
+}
+
+/// A full-screen view, shown from `open` until dropped.
+pub struct Screen {
+    gui: Gui,
+    view_port: *mut sys::ViewPort,
+    /// Boxed so its address stays put while the callbacks hold a pointer to it.
+    shared: Box<Shared>,
+}
+
+impl Screen {
+    /// Show `header` and `text` on top of everything else.
+    pub fn open(header: &str, text: &str) -> Self {
+        let shared = Box::new(Shared {
+            content: Mutex::new(Content {
+                header: c_string(header),
+                text: c_string(text),
+            }),
+            events: MessageQueue::new(EVENT_QUEUE_LEN),
+        });
+        let gui = Gui::open();
+
+        let view_port = unsafe {
+            let view_port = sys::view_port_alloc();
+            let context = &*shared as *const Shared as *mut c_void;
+            sys::view_port_draw_callback_set(view_port, Some(draw_callback), context);
+            sys::view_port_input_callback_set(view_port, Some(input_callback), context);
+            sys::gui_add_view_port(gui.as_ptr(), view_port, sys::GuiLayerFullscreen);
+            view_port
+        };
+
+        Self { gui, view_port, shared }
+    }
+
+    /// Replace the text under the header.
+    pub fn set_text(&self, text: &str) {
+        self.shared.content.lock().text = c_string(text);
+        unsafe { sys::view_port_update(self.view_port) };
+    }
+
+    /// Wait for a key to be pressed or released.
+    pub fn wait_event(&self) -> KeyEvent {
+        loop {
+            if let Ok(event) = self.shared.events.get(FuriDuration::MAX) {
+                return event;
+            }
+        }
+    }
+
+    /// Wait up to `timeout_ms` for a key to be pressed or released.
+    pub fn next_event(&self, timeout_ms: u32) -> Option<KeyEvent> {
+        self.shared.events.get(FuriDuration::from_millis(timeout_ms as u64)).ok()
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        // Unhook the callbacks before the state they point at is freed
+        unsafe {
+            sys::view_port_enabled_set(self.view_port, false);
+            sys::gui_remove_view_port(self.gui.as_ptr(), self.view_port);
+            sys::view_port_free(self.view_port);
+        }
+    }
+}
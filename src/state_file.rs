@@ -0,0 +1,808 @@
+//! The state file and its backups — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! Everything about `state.conf` that isn't touching the SD card: its
+//! FlipperFormat layout, migrating v1 files, atomic saves with rotating
+//! backups, and quarantining files that won't read. Files are streamed a line
+//! at a time both ways, so however many blinds there are, the file never has
+//! to fit in memory whole. The card itself is a `FileSystem`, so all of it
+//! runs against an in-memory one in host tests. Nine lives, three backups :3
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+use crate::blind::{
+    BlindType, DEFAULT_REPEATS, MAX_CODE_BLOCK, MAX_REPEATS, SomfyBlind, TravelTimes,
+};
+use crate::flipper_format::{LineSource, Reader, Writer};
+use crate::protocol::{FrameLength, KeyPolicy, SomfyAddress, SomfyTimings};
+use crate::radio::{Preset, SOMFY_FREQUENCY_HZ, is_valid_frequency};
+
+/// Heap the blinds may take up unless the settings say otherwise — room for
+/// a building's worth, with plenty left over for the rest of the app.
+pub const DEFAULT_BLIND_MEMORY: usize = 8 * 1024;
+
+/// Path to the state file on the Flipper's SD card.
+/// Matches the C app's APP_DATA_PATH("state.conf") with appid="somfy_rts".
+pub const STATE_PATH: &str = "/ext/apps_data/somfy_rts/state.conf";
+
+/// Saves are written here in full, then renamed over the state file.
+const STATE_TMP_PATH: &str = "/ext/apps_data/somfy_rts/state.conf.tmp";
+
+/// Earlier saves, newest first; the oldest drops off the end on each save.
+const STATE_BACKUP_PATHS: [&str; 3] = [
+    "/ext/apps_data/somfy_rts/state.conf.bak",
+    "/ext/apps_data/somfy_rts/state.conf.bak.1",
+    "/ext/apps_data/somfy_rts/state.conf.bak.2",
+];
+
+/// File type header — must match the C app exactly.
+const STATE_FILETYPE: &str = "Somfy RTS State";
+
+/// File format version written by default.
+const STATE_VERSION: u32 = 2;
+
+/// The version the C app writes and insists on.
+const STATE_VERSION_V1: u32 = 1;
+
+/// The address the first new remote gets, as the app has always numbered them.
+const FIRST_NEW_ADDRESS: u32 = 0x100002;
+
+/// Most quarantined state files kept before new ones are left where they are.
+const MAX_QUARANTINED: u32 = 99;
+
+/// Collection of all known blinds — the whole litter, if you will :3
+pub struct SomfyState {
+    pub blinds: Vec<SomfyBlind>,
+    /// Rolling codes reserved per state file write. Bigger blocks mean fewer
+    /// SD writes, at the cost of skipping up to a block's worth after a crash.
+    pub code_block: u16,
+    /// The version the next save writes; set from the app settings.
+    pub format: StateFormat,
+    /// Bytes of heap new blinds may take up; set from the app settings.
+    ///
+    /// Only adding blinds checks it. A file is always loaded whole, as far as
+    /// memory allows, so no blind is ever dropped by the next save.
+    pub blind_memory: usize,
+}
+
+impl SomfyState {
+    pub fn new() -> Self {
+        Self {
+            blinds: Vec::new(),
+            code_block: 1,
+            format: StateFormat::default(),
+            blind_memory: DEFAULT_BLIND_MEMORY,
+        }
+    }
+
+    /// How many blinds `blind_memory` has room for.
+    pub fn max_blinds(&self) -> usize {
+        self.blind_memory / core::mem::size_of::<SomfyBlind>()
+    }
+
+    /// No room for another blind.
+    pub fn is_full(&self) -> bool {
+        self.blinds.len() >= self.max_blinds()
+    }
+
+    /// Add `blind` if the budget has room, the heap can spare it and no other
+    /// blind has its address.
+    pub fn add(&mut self, blind: SomfyBlind) -> Result<(), AddError> {
+        if self.uses_address(blind.address) {
+            return Err(AddError::DuplicateAddress);
+        }
+        if self.is_full() || self.blinds.try_reserve(1).is_err() {
+            return Err(AddError::Full);
+        }
+        self.blinds.push(blind);
+        Ok(())
+    }
+
+    /// Whether a blind already has `address`.
+    pub fn uses_address(&self, address: SomfyAddress) -> bool {
+        self.blinds.iter().any(|blind| blind.address == address)
+    }
+
+    /// The lowest address for a new remote that no blind has, if any is left.
+    pub fn unused_address(&self) -> Option<SomfyAddress> {
+        (FIRST_NEW_ADDRESS..=SomfyAddress::MAX)
+            .map_while(|address| SomfyAddress::new(address).ok())
+            .find(|&address| !self.uses_address(address))
+    }
+}
+
+/// Why a blind couldn't be added.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddError {
+    /// No room left in the blind memory budget, or on the heap.
+    Full,
+    /// Another blind has the same address. Each would keep its own rolling
+    /// code for the one motor, and whichever sent less would fall out of sync.
+    DuplicateAddress,
+}
+
+/// Which state file version to write.
+///
+/// Both carry every key; the C app skips the ones it doesn't know but refuses
+/// any version but 1, so `V1` keeps a card usable with it.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum StateFormat {
+    V1,
+    #[default]
+    V2,
+}
+
+impl StateFormat {
+    /// Parse the settings file spelling: `v1` or `v2`.
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "v1" => Some(StateFormat::V1),
+            "v2" => Some(StateFormat::V2),
+            _ => None,
+        }
+    }
+
+    /// The settings file spelling, the inverse of `parse`.
+    pub fn encode(self) -> &'static str {
+        match self {
+            StateFormat::V1 => "v1",
+            StateFormat::V2 => "v2",
+        }
+    }
+}
+
+/// Why the state file couldn't be loaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadError {
+    /// The file is there but wouldn't open.
+    Open,
+    /// It isn't a `Somfy RTS State` file.
+    WrongFileType,
+    /// Written by a version of the app this one doesn't know.
+    UnsupportedVersion(u32),
+    /// It stops part way through, or a blind is missing a key it must have.
+    Truncated,
+    /// A blind's address is wider than 24 bits.
+    BadAddress,
+    /// More blinds than there's heap to hold. The file itself is fine.
+    OutOfMemory,
+}
+
+impl LoadError {
+    /// A stable one-word name, for the log.
+    pub fn code(self) -> &'static str {
+        match self {
+            LoadError::Open => "open",
+            LoadError::WrongFileType => "wrong_file_type",
+            LoadError::UnsupportedVersion(_) => "unsupported_version",
+            LoadError::Truncated => "truncated",
+            LoadError::BadAddress => "bad_address",
+            LoadError::OutOfMemory => "out_of_memory",
+        }
+    }
+}
+
+/// The handful of file operations the state file needs.
+///
+/// The SD card on the device; a map of paths to contents in tests.
+pub trait FileSystem {
+    /// A file open for reading, a line at a time.
+    type Lines: LineSource;
+
+    fn exists(&mut self, path: &str) -> bool;
+
+    /// Open the file for reading, or `None` if it can't be.
+    fn open(&mut self, path: &str) -> Option<Self::Lines>;
+
+    /// Replace the file with whatever `contents` writes to it. True only once
+    /// all of it is stored.
+    fn write(
+        &mut self,
+        path: &str,
+        contents: impl FnOnce(&mut dyn Write) -> fmt::Result,
+    ) -> bool;
+
+    /// Move `from` to `to`, replacing whatever `to` held. False, with
+    /// nothing changed, if `from` doesn't exist or the move fails.
+    fn rename(&mut self, from: &str, to: &str) -> bool;
+
+    /// Delete the file; true if it's gone, including if it never existed.
+    fn remove(&mut self, path: &str) -> bool;
+
+    /// `write` by way of `<path>.tmp`, renamed over the file only once all of
+    /// it is stored, so a power cut part way leaves the old one whole.
+    fn replace(
+        &mut self,
+        path: &str,
+        contents: impl FnOnce(&mut dyn Write) -> fmt::Result,
+    ) -> bool {
+        let mut tmp = String::new();
+        let _ = write!(tmp, "{path}.tmp");
+        self.write(&tmp, contents) && self.rename(&tmp, path)
+    }
+}
+
+/// Read a state file, one blind at a time.
+///
+/// A v1 file is a v2 file missing keys; the defaults fill them in and the
+/// next save writes it out as v2.
+pub fn read_state(mut ff: Reader<impl LineSource>) -> Result<SomfyState, LoadError> {
+    let mut state = SomfyState::new();
+
+    let version = ff.read_header(STATE_FILETYPE).ok_or(LoadError::WrongFileType)?;
+    if !(STATE_VERSION_V1..=STATE_VERSION).contains(&version) {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    // Files from before code blocks existed reserve one code per send
+    if let Some(block) = ff.optional(|ff| ff.read_uint32("CodeBlock")) {
+        state.code_block = block.clamp(1, MAX_CODE_BLOCK as u32) as u16;
+    }
+
+    let count = ff.read_uint32("Count").ok_or(LoadError::Truncated)?;
+
+    // Read each blind's data — one kitty at a time. The count comes from the
+    // file, so the list grows as blinds turn up rather than trusting it
+    for _ in 0..count {
+        let name = ff.read_string("Name").map(truncated).ok_or(LoadError::Truncated)?;
+        let address = ff.read_uint32("Address").ok_or(LoadError::Truncated)?;
+        let rolling_code = ff.read_uint32("RollingCode").ok_or(LoadError::Truncated)?;
+
+        // Keys the C app doesn't write. Each is read with a rewind on a miss,
+        // so the next blind still parses
+        let frame_length = match ff.optional(|ff| ff.read_uint32("FrameBits")) {
+            Some(80) => FrameLength::Extended,
+            _ => FrameLength::Standard,
+        };
+        // Blinds saved before key policies existed always sent 0xA0
+        let key_policy = ff
+            .optional(|ff| ff.read_string("KeyPolicy").map(KeyPolicy::parse))
+            .map_or(KeyPolicy::Fixed, Option::unwrap_or_default);
+        let mut timing_values = [0u32; 8];
+        let timings = match read_optional_array(&mut ff, "Timings", &mut timing_values) {
+            true => SomfyTimings::from_array(timing_values).unwrap_or_default(),
+            false => SomfyTimings::DEFAULT,
+        };
+        // Frequencies outside the CC1101's bands would only ever fail to send
+        let frequency = ff
+            .optional(|ff| ff.read_uint32("Frequency"))
+            .filter(|&hz| is_valid_frequency(hz))
+            .unwrap_or(SOMFY_FREQUENCY_HZ);
+        let preset = ff
+            .optional(|ff| ff.read_string("Preset").map(Preset::parse))
+            .map_or(Preset::Ook650Async, Option::unwrap_or_default);
+        let room = ff.optional(|ff| ff.read_string("Room").map(truncated)).unwrap_or_default();
+        let blind_type = ff
+            .optional(|ff| ff.read_string("BlindType").map(BlindType::parse))
+            .flatten()
+            .unwrap_or_default();
+        let repeats = ff
+            .optional(|ff| ff.read_uint32("Repeats"))
+            .map_or(DEFAULT_REPEATS, |n| n.clamp(1, MAX_REPEATS as u32) as u8);
+        // -1 (or anything else out of range) means no favourite is known
+        let favourite = ff
+            .optional(|ff| ff.read_int32("Favourite"))
+            .and_then(|position| u8::try_from(position).ok())
+            .filter(|&position| position <= 100);
+        let mut travel = [0u32; 2];
+        let has_travel = read_optional_array(&mut ff, "TravelTimes", &mut travel);
+        // It's the last key we write per blind, so a v2 file without it was
+        // cut off part way
+        if version == STATE_VERSION && !has_travel {
+            return Err(LoadError::Truncated);
+        }
+
+        // The C app only ever writes 24-bit addresses — anything wider is
+        // corrupt, and truncating it would talk to some other motor. Skipping
+        // the blind would have the next save drop it for good
+        let address = SomfyAddress::new(address).map_err(|_| LoadError::BadAddress)?;
+
+        let mut blind = SomfyBlind::new(name, address, rolling_code as u16);
+        blind.frame_length = frame_length;
+        blind.key_policy = key_policy;
+        blind.timings = timings;
+        blind.frequency = frequency;
+        blind.preset = preset;
+        blind.room = room;
+        blind.blind_type = blind_type;
+        blind.repeats = repeats;
+        blind.favourite = favourite;
+        blind.travel = TravelTimes { up_ms: travel[0], down_ms: travel[1] };
+        state.blinds.try_reserve(1).map_err(|_| LoadError::OutOfMemory)?;
+        state.blinds.push(blind);
+    }
+
+    Ok(state)
+}
+
+/// `Reader::read_uint32_array`, rewinding on a miss like `Reader::optional`.
+fn read_optional_array(
+    ff: &mut Reader<impl LineSource>,
+    key: &str,
+    values: &mut [u32],
+) -> bool {
+    ff.optional(|ff| ff.read_uint32_array(key, values).then_some(())).is_some()
+}
+
+/// Cut `text` down to what fits, at a char boundary — better than losing the
+/// whole blind over a long name.
+fn truncated<const N: usize>(text: &str) -> heapless::String<N> {
+    let mut out = heapless::String::new();
+    for c in text.chars() {
+        if out.push(c).is_err() {
+            break;
+        }
+    }
+    out
+}
+
+/// Write out the whole state, every key for every blind.
+///
+/// Keys are written even when they hold defaults: reads search forward, so a
+/// key missing from one blind would be taken from the next.
+pub fn write_state(out: impl Write, state: &SomfyState) -> fmt::Result {
+    let mut ff = Writer::new(out);
+    let version = match state.format {
+        StateFormat::V1 => STATE_VERSION_V1,
+        StateFormat::V2 => STATE_VERSION,
+    };
+    ff.write_header(STATE_FILETYPE, version)?;
+    ff.write_uint32("CodeBlock", &[state.code_block as u32])?;
+    ff.write_uint32("Count", &[state.blinds.len() as u32])?;
+
+    // Write each blind — herding cats, but in a loop
+    for blind in state.blinds.iter() {
+        ff.write_string("Name", &blind.name)?;
+        ff.write_uint32("Address", &[blind.address.get()])?;
+        // Past any codes reserved for sending, so none can be reissued
+        ff.write_uint32("RollingCode", &[blind.stored_rolling_code() as u32])?;
+        ff.write_uint32("FrameBits", &[blind.frame_length.bits() as u32])?;
+        ff.write_string("KeyPolicy", &blind.key_policy.encode())?;
+        ff.write_uint32("Timings", &blind.timings.to_array())?;
+        ff.write_uint32("Frequency", &[blind.frequency])?;
+        ff.write_string("Preset", blind.preset.name())?;
+        ff.write_string("Room", &blind.room)?;
+        ff.write_string("BlindType", blind.blind_type.encode())?;
+        ff.write_uint32("Repeats", &[blind.repeats as u32])?;
+        ff.write_int32("Favourite", &[blind.favourite.map_or(-1, i32::from)])?;
+        ff.write_uint32("TravelTimes", &[blind.travel.up_ms, blind.travel.down_ms])?;
+    }
+    Ok(())
+}
+
+/// Read one state file. `Ok(None)` if there's no such file.
+fn load_file(fs: &mut impl FileSystem, path: &str) -> Result<Option<SomfyState>, LoadError> {
+    if !fs.exists(path) {
+        return Ok(None);
+    }
+    let lines = fs.open(path).ok_or(LoadError::Open)?;
+    read_state(Reader::from_source(lines)).map(Some)
+}
+
+/// Load the state file.
+///
+/// No state file at all is a fresh start — unless a save died between its
+/// renames, leaving a .tmp behind, in which case `recover` picks up the
+/// pieces. Backups alone aren't enough: the file may have been deleted on
+/// purpose, or quarantined and the user chose to leave it. A file that is
+/// there but unreadable is moved aside to `state.conf.corrupt-N` before the
+/// error is returned, so no later save can overwrite it; what happens next is
+/// up to the user. Running out of memory says nothing about the file, so
+/// that one is left where it is.
+pub fn load(fs: &mut impl FileSystem) -> Result<SomfyState, LoadError> {
+    match load_file(fs, STATE_PATH) {
+        Ok(Some(state)) => Ok(state),
+        Ok(None) if fs.exists(STATE_TMP_PATH) => Ok(recover(fs).unwrap_or_else(SomfyState::new)),
+        Ok(None) => Ok(SomfyState::new()),
+        Err(LoadError::OutOfMemory) => Err(LoadError::OutOfMemory),
+        Err(error) => {
+            quarantine(fs);
+            Err(error)
+        }
+    }
+}
+
+/// The newest earlier save that reads back whole, if any.
+pub fn recover(fs: &mut impl FileSystem) -> Option<SomfyState> {
+    // A save that died between its renames leaves the new file as .tmp. It
+    // may also be a stale leftover, so like the backups it's treated as one
+    // save behind whatever was lost.
+    let fallbacks = [(STATE_TMP_PATH, 1)]
+        .into_iter()
+        .chain(STATE_BACKUP_PATHS.into_iter().zip(1..));
+    for (path, saves_behind) in fallbacks {
+        let Ok(Some(mut state)) = load_file(fs, path) else {
+            continue;
+        };
+        // Every save reserves at most one block past the file before it, so
+        // skipping that many per lost save keeps clear of codes already sent
+        let skip = state.code_block.saturating_mul(saves_behind);
+        for blind in state.blinds.iter_mut() {
+            blind.skip_rolling_codes(skip);
+        }
+        return Some(state);
+    }
+
+    None
+}
+
+/// Rename the state file to the first free `state.conf.corrupt-N`.
+///
+/// If that fails too, the file still survives the next save as a backup.
+fn quarantine(fs: &mut impl FileSystem) -> bool {
+    for n in 1..=MAX_QUARANTINED {
+        let mut path = String::new();
+        let _ = write!(path, "{STATE_PATH}.corrupt-{n}");
+        if !fs.exists(&path) {
+            return fs.rename(STATE_PATH, &path);
+        }
+    }
+    false
+}
+
+/// Save the state.
+///
+/// The new state is written to a temporary file first and only renamed into
+/// place once it's all stored, so a power cut mid-save never leaves nothing
+/// behind. The file it replaces becomes the newest backup.
+pub fn save(fs: &mut impl FileSystem, state: &SomfyState) -> bool {
+    if !fs.write(STATE_TMP_PATH, |out| write_state(out, state)) {
+        return false;
+    }
+
+    // Shuffle the backups down one, oldest first so each lands on one that
+    // has already moved on. With nothing to move into a slot, whatever was
+    // there is now older than its place says, and `recover` would skip too
+    // few codes for it — so it goes
+    let moves = STATE_BACKUP_PATHS.windows(2).rev().map(|pair| (pair[0], pair[1]));
+    for (from, to) in moves.chain([(STATE_PATH, STATE_BACKUP_PATHS[0])]) {
+        if !fs.rename(from, to) {
+            fs.remove(to);
+        }
+    }
+
+    // Until this lands there's no state file, and `load` finds the .tmp
+    fs.rename(STATE_TMP_PATH, STATE_PATH)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::flipper_format::TextLines;
+
+    /// A file system in memory, with switches to make operations fail.
+    #[derive(Default)]
+    struct MemoryFs {
+        files: BTreeMap<String, String>,
+        fail_writes: bool,
+        /// Fail renames onto this path, standing in for a power cut.
+        fail_rename_to: Option<&'static str>,
+    }
+
+    impl FileSystem for MemoryFs {
+        type Lines = TextLines<String>;
+
+        fn exists(&mut self, path: &str) -> bool {
+            self.files.contains_key(path)
+        }
+
+        fn open(&mut self, path: &str) -> Option<Self::Lines> {
+            self.files.get(path).cloned().map(TextLines::new)
+        }
+
+        fn write(
+            &mut self,
+            path: &str,
+            contents: impl FnOnce(&mut dyn Write) -> fmt::Result,
+        ) -> bool {
+            let mut text = String::new();
+            if self.fail_writes || contents(&mut text).is_err() {
+                return false;
+            }
+            self.files.insert(path.to_string(), text);
+            true
+        }
+
+        /// Replaces `to`, like the firmware's `storage_common_rename`.
+        fn rename(&mut self, from: &str, to: &str) -> bool {
+            if self.fail_rename_to == Some(to) {
+                return false;
+            }
+            let Some(contents) = self.files.remove(from) else {
+                return false;
+            };
+            self.files.insert(to.to_string(), contents);
+            true
+        }
+
+        fn remove(&mut self, path: &str) -> bool {
+            self.files.remove(path);
+            true
+        }
+    }
+
+    fn parse_state(text: &str) -> Result<SomfyState, LoadError> {
+        read_state(Reader::new(text))
+    }
+
+    fn blind(name: &str, address: u32, rolling_code: u16) -> SomfyBlind {
+        let name = heapless::String::try_from(name).unwrap();
+        SomfyBlind::new(name, SomfyAddress::new(address).unwrap(), rolling_code)
+    }
+
+    fn two_blinds() -> SomfyState {
+        let mut state = SomfyState::new();
+        let mut living_room = blind("Living Room", 0x18F1C7, 42);
+        living_room.room = heapless::String::try_from("Downstairs").unwrap();
+        living_room.blind_type = BlindType::Venetian;
+        living_room.favourite = Some(40);
+        living_room.travel = TravelTimes { up_ms: 21000, down_ms: 19500 };
+        let mut bedroom = blind("Bedroom", 0x18F1C8, 17);
+        bedroom.key_policy = KeyPolicy::Fixed;
+        bedroom.frequency = 433_920_000;
+        bedroom.repeats = 2;
+        state.blinds.push(living_room);
+        state.blinds.push(bedroom);
+        state
+    }
+
+    fn written(state: &SomfyState) -> String {
+        let mut out = String::new();
+        write_state(&mut out, state).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_write_state_matches_golden_file() {
+        let golden = include_str!("../tests/fixtures/state_v2.conf");
+        assert_eq!(written(&two_blinds()), golden);
+    }
+
+    #[test]
+    fn test_golden_file_round_trips() {
+        let golden = include_str!("../tests/fixtures/state_v2.conf");
+        let state = parse_state(golden).unwrap();
+        assert_eq!(written(&state), golden);
+
+        let living_room = &state.blinds[0];
+        assert_eq!(living_room.room.as_str(), "Downstairs");
+        assert_eq!(living_room.blind_type, BlindType::Venetian);
+        assert_eq!(living_room.favourite, Some(40));
+        assert_eq!(state.blinds[1].frequency, 433_920_000);
+        assert_eq!(state.blinds[1].repeats, 2);
+    }
+
+    #[test]
+    fn test_reads_c_app_file() {
+        let state = parse_state(include_str!("../tests/fixtures/state_v1_c_app.conf")).unwrap();
+        assert_eq!(state.blinds.len(), 2);
+
+        let b = &state.blinds[0];
+        assert_eq!(b.name.as_str(), "Living Room");
+        assert_eq!(b.address.get(), 1634567);
+        assert_eq!(b.rolling_code, 42);
+        // Everything the C app doesn't know about comes out as it behaves
+        assert_eq!(b.key_policy, KeyPolicy::Fixed);
+        assert_eq!(b.frequency, SOMFY_FREQUENCY_HZ);
+        assert_eq!(b.repeats, DEFAULT_REPEATS);
+        assert_eq!(b.favourite, None);
+        assert_eq!(state.blinds[1].rolling_code, 17);
+    }
+
+    #[test]
+    fn test_v1_output_keeps_c_app_keys_in_order() {
+        let mut state = parse_state(include_str!("../tests/fixtures/state_v1_c_app.conf")).unwrap();
+        state.format = StateFormat::V1;
+        let text = written(&state);
+        assert!(text.starts_with("Filetype: Somfy RTS State\nVersion: 1\n"));
+
+        // What the C app reads, in the order it reads it
+        let mut ff = Reader::new(&text);
+        assert_eq!(ff.read_uint32("Count"), Some(2));
+        for (name, address, code) in [("Living Room", 1634567, 42), ("Bedroom", 1634568, 17)] {
+            assert_eq!(ff.read_string("Name"), Some(name));
+            assert_eq!(ff.read_uint32("Address"), Some(address));
+            assert_eq!(ff.read_uint32("RollingCode"), Some(code));
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_state("").err(), Some(LoadError::WrongFileType));
+        let other = "Filetype: Flipper SubGhz RAW File\nVersion: 1\n";
+        assert_eq!(parse_state(other).err(), Some(LoadError::WrongFileType));
+        let future = "Filetype: Somfy RTS State\nVersion: 3\nCount: 0\n";
+        assert_eq!(parse_state(future).err(), Some(LoadError::UnsupportedVersion(3)));
+
+        let golden = include_str!("../tests/fixtures/state_v2.conf");
+        let cut = &golden[..golden.rfind("TravelTimes").unwrap()];
+        assert_eq!(parse_state(cut).err(), Some(LoadError::Truncated));
+        let cut = &golden[..golden.rfind("Name").unwrap()];
+        assert_eq!(parse_state(cut).err(), Some(LoadError::Truncated));
+    }
+
+    #[test]
+    fn test_wide_address_is_an_error_not_a_lost_blind() {
+        let golden = include_str!("../tests/fixtures/state_v2.conf");
+        // 0x1000000, one bit past what a Somfy address holds
+        let wide = golden.replace("Address: 1634760", "Address: 16777216");
+        assert_eq!(parse_state(&wide).err(), Some(LoadError::BadAddress));
+
+        let mut fs = MemoryFs::default();
+        fs.files.insert(STATE_PATH.to_string(), wide);
+        assert_eq!(load(&mut fs).err(), Some(LoadError::BadAddress));
+        assert!(fs.exists("/ext/apps_data/somfy_rts/state.conf.corrupt-1"));
+    }
+
+    #[test]
+    fn test_save_rotates_backups() {
+        let mut fs = MemoryFs::default();
+        let mut state = two_blinds();
+        for code in [1, 2, 3, 4, 5] {
+            state.blinds[0].rolling_code = code;
+            assert!(save(&mut fs, &state));
+        }
+
+        let code = |fs: &mut MemoryFs, path: &str| {
+            parse_state(&fs.files[path]).unwrap().blinds[0].rolling_code
+        };
+        // Renames replace, so shuffling newest first would have moved .bak
+        // over .bak.1 before .bak.1 got out of the way
+        assert_eq!(code(&mut fs, STATE_PATH), 5);
+        assert_eq!(code(&mut fs, STATE_BACKUP_PATHS[0]), 4);
+        assert_eq!(code(&mut fs, STATE_BACKUP_PATHS[1]), 3);
+        assert_eq!(code(&mut fs, STATE_BACKUP_PATHS[2]), 2);
+        assert!(!fs.exists(STATE_TMP_PATH));
+    }
+
+    #[test]
+    fn test_save_drops_a_backup_with_nothing_newer() {
+        let mut fs = MemoryFs::default();
+        let mut state = two_blinds();
+        for code in [1, 2, 3, 4] {
+            state.blinds[0].rolling_code = code;
+            assert!(save(&mut fs, &state));
+        }
+        fs.files.remove(STATE_BACKUP_PATHS[1]);
+
+        state.blinds[0].rolling_code = 5;
+        assert!(save(&mut fs, &state));
+        // The old .bak.2 (code 1) would now be four saves behind, not three
+        assert!(!fs.exists(STATE_BACKUP_PATHS[2]));
+        assert!(fs.exists(STATE_BACKUP_PATHS[1]));
+    }
+
+    #[test]
+    fn test_replace_keeps_the_old_file_until_the_new_one_is_stored() {
+        let mut fs = MemoryFs::default();
+        assert!(fs.replace("/settings.conf", |out| out.write_str("Radio: internal\n")));
+        assert!(fs.replace("/settings.conf", |out| out.write_str("Radio: external\n")));
+        assert_eq!(fs.files["/settings.conf"], "Radio: external\n");
+        assert!(!fs.exists("/settings.conf.tmp"));
+
+        let cut_off = fs.replace("/settings.conf", |out| {
+            out.write_str("Radio: ")?;
+            Err(fmt::Error)
+        });
+        assert!(!cut_off);
+        assert_eq!(fs.files["/settings.conf"], "Radio: external\n");
+    }
+
+    #[test]
+    fn test_failed_write_leaves_state_alone() {
+        let mut fs = MemoryFs::default();
+        assert!(save(&mut fs, &two_blinds()));
+        let before = fs.files.clone();
+
+        fs.fail_writes = true;
+        assert!(!save(&mut fs, &SomfyState::new()));
+        assert_eq!(fs.files, before);
+        assert_eq!(load(&mut fs).unwrap().blinds.len(), 2);
+    }
+
+    #[test]
+    fn test_power_cut_between_renames_recovers_the_new_save() {
+        let mut fs = MemoryFs::default();
+        let mut state = two_blinds();
+        assert!(save(&mut fs, &state));
+
+        state.blinds[0].rolling_code = 100;
+        fs.fail_rename_to = Some(STATE_PATH);
+        assert!(!save(&mut fs, &state));
+        assert!(!fs.exists(STATE_PATH));
+
+        let loaded = load(&mut fs).unwrap();
+        // Treated as one save behind, so a block is skipped to be safe
+        assert_eq!(loaded.blinds[0].rolling_code, 101);
+    }
+
+    #[test]
+    fn test_corrupt_state_is_quarantined_not_overwritten() {
+        let mut fs = MemoryFs::default();
+        let mut state = two_blinds();
+        state.code_block = 4;
+        assert!(save(&mut fs, &state));
+        state.blinds[0].rolling_code = 50;
+        assert!(save(&mut fs, &state));
+        let cut_off = "Filetype: Somfy RTS State\nVersion: 2\nCou";
+        fs.files.insert(STATE_PATH.to_string(), cut_off.into());
+
+        assert_eq!(load(&mut fs).err(), Some(LoadError::Truncated));
+        assert!(!fs.exists(STATE_PATH));
+        let quarantined = "/ext/apps_data/somfy_rts/state.conf.corrupt-1";
+        assert!(fs.files[quarantined].ends_with("Cou"));
+
+        // Starting over keeps it; a second bad file goes next to it
+        assert_eq!(load(&mut fs).unwrap().blinds.len(), 0);
+        fs.files.insert(STATE_PATH.to_string(), "garbage".into());
+        assert_eq!(load(&mut fs).err(), Some(LoadError::WrongFileType));
+        assert!(fs.exists("/ext/apps_data/somfy_rts/state.conf.corrupt-2"));
+
+        // The backup is the save before the lost one, so it skips a block
+        let recovered = recover(&mut fs).unwrap();
+        assert_eq!(recovered.blinds[0].rolling_code, 42 + 4);
+    }
+
+    #[test]
+    fn test_nothing_saved_yet() {
+        let mut fs = MemoryFs::default();
+        assert_eq!(load(&mut fs).unwrap().blinds.len(), 0);
+        assert!(recover(&mut fs).is_none());
+    }
+
+    #[test]
+    fn test_forty_blinds_round_trip() {
+        let mut fs = MemoryFs::default();
+        let mut state = SomfyState::new();
+        for n in 0..40 {
+            let mut name = heapless::String::new();
+            let _ = write!(name, "Window {}", n + 1);
+            let address = SomfyAddress::new(0x100000 + n).unwrap();
+            assert_eq!(state.add(SomfyBlind::new(name, address, n as u16 + 1)), Ok(()));
+        }
+        assert!(save(&mut fs, &state));
+
+        let loaded = load(&mut fs).unwrap();
+        assert_eq!(loaded.blinds.len(), 40);
+        assert_eq!(loaded.blinds[39].name.as_str(), "Window 40");
+        assert_eq!(loaded.blinds[39].rolling_code, 40);
+    }
+
+    #[test]
+    fn test_one_blind_per_address() {
+        let mut state = SomfyState::new();
+        assert_eq!(state.unused_address(), SomfyAddress::new(FIRST_NEW_ADDRESS).ok());
+        assert_eq!(state.add(blind("Imported", FIRST_NEW_ADDRESS, 7)), Ok(()));
+        let again = state.add(blind("Again", FIRST_NEW_ADDRESS, 9));
+        assert_eq!(again, Err(AddError::DuplicateAddress));
+        assert_eq!(state.add(blind("Third", FIRST_NEW_ADDRESS + 2, 1)), Ok(()));
+
+        // New remotes fill the gaps rather than counting blinds
+        assert_eq!(state.unused_address(), SomfyAddress::new(FIRST_NEW_ADDRESS + 1).ok());
+        state.blinds.remove(0);
+        assert_eq!(state.unused_address(), SomfyAddress::new(FIRST_NEW_ADDRESS).ok());
+    }
+
+    #[test]
+    fn test_blind_memory_budget() {
+        let mut state = SomfyState::new();
+        state.blind_memory = core::mem::size_of::<SomfyBlind>() * 2;
+        assert_eq!(state.max_blinds(), 2);
+        assert_eq!(state.add(blind("One", 1, 1)), Ok(()));
+        assert_eq!(state.add(blind("Two", 2, 1)), Ok(()));
+        assert!(state.is_full());
+        assert_eq!(state.add(blind("Three", 3, 1)), Err(AddError::Full));
+
+        // A smaller budget never drops blinds that are already there
+        state.blind_memory = 0;
+        let mut fs = MemoryFs::default();
+        assert!(save(&mut fs, &state));
+        assert_eq!(load(&mut fs).unwrap().blinds.len(), 2);
+    }
+}
@@ -1,231 +1,587 @@
 //! Storage persistence for Somfy blind state — saves and loads from FlipperFormat files.
 //!
 //! Uses the same file format as the C app so state is shared between both versions.
-//! Think of it as a cat-alog of your blinds, purr-sisted to disk :3
+//! The formats themselves live in `flipper_format` and `state_file`; this is the
+//! part that actually touches the SD card. Think of it as a cat-alog of your
+//! blinds, purr-sisted to disk :3
 
-use core::ffi::{c_char, CStr};
-use heapless::{String, Vec};
+use alloc::ffi::CString;
+use core::ffi::{CStr, c_void};
+use core::fmt::{self, Write};
+use heapless::String;
 
-pub const MAX_BLINDS: usize = 8;
-pub const MAX_NAME_LEN: usize = 20;
+use crate::blind::{MAX_NAME_LEN, SomfyBlind};
+use crate::flipper_format::{LineSource, Reader, Writer};
+use crate::protocol::{SomfyCommand, SomfyFrame};
+use crate::radio::{ListenBeforeTalk, RadioDevice, TxError};
+use crate::state_file::{self, FileSystem};
+use crate::sub_file::{self, ParseError};
+use crate::tx_log::{self, Timestamp, TxLogEntry};
 
-/// Path to the state file on the Flipper's SD card.
-/// Matches the C app's APP_DATA_PATH("state.conf") with appid="somfy_rts".
-const STATE_PATH: &CStr = c"/ext/apps_data/somfy_rts/state.conf";
+pub use crate::state_file::{AddError, DEFAULT_BLIND_MEMORY, LoadError, SomfyState, StateFormat};
 
-/// File type header — must match the C app exactly.
-const STATE_FILETYPE: &CStr = c"Somfy RTS State";
+/// App settings live next to the state file, so the C app never sees them.
+const SETTINGS_PATH: &str = "/ext/apps_data/somfy_rts/settings.conf";
 
-/// File format version.
-const STATE_VERSION: u32 = 1;
+/// Settings file type header.
+const SETTINGS_FILETYPE: &str = "Somfy RTS Settings";
 
-/// A single blind's persisted state — name, address, and rolling code.
-pub struct SomfyBlind {
-    pub name: String<MAX_NAME_LEN>,
-    pub address: u32,
-    pub rolling_code: u16,
-}
+/// Settings file format version.
+const SETTINGS_VERSION: u32 = 1;
+
+/// Settings files are a few short lines; anything bigger isn't one.
+const MAX_SETTINGS_FILE_BYTES: usize = 1024;
+
+/// Longest line kept when reading a file a line at a time; the rest is
+/// skipped. State file lines are well under 80 bytes.
+const MAX_LINE_BYTES: usize = 256;
+
+/// Key files are a handful of short lines; anything bigger isn't one.
+const MAX_KEY_FILE_BYTES: usize = 1024;
+
+/// One line per transmission, newest last.
+const TX_LOG_PATH: &str = "/ext/apps_data/somfy_rts/tx.log";
 
-/// Collection of all known blinds — the whole litter, if you will :3
-pub struct SomfyState {
-    pub blinds: Vec<SomfyBlind, MAX_BLINDS>,
+/// Where the log goes once it's full — one generation is kept.
+const TX_LOG_OLD_PATH: &str = "/ext/apps_data/somfy_rts/tx.log.old";
+
+/// The log is rotated once it grows past this (a couple of hundred lines).
+const MAX_TX_LOG_BYTES: u64 = 16 * 1024;
+
+/// App-wide preferences — how the cat likes its cushions arranged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// Which radio to transmit and receive with.
+    pub radio: RadioDevice,
+    /// Whether to wait for a quiet channel before transmitting, and for how long.
+    pub listen_before_talk: ListenBeforeTalk,
+    /// State file version to write — `V1` for sharing the card with the C app.
+    pub state_format: StateFormat,
+    /// Bytes of heap the blinds may take up, which caps how many can be added.
+    pub blind_memory: u32,
 }
 
-impl SomfyState {
-    pub fn new() -> Self {
+impl Default for Settings {
+    fn default() -> Self {
         Self {
-            blinds: Vec::new(),
+            radio: RadioDevice::default(),
+            listen_before_talk: ListenBeforeTalk::default(),
+            state_format: StateFormat::default(),
+            blind_memory: DEFAULT_BLIND_MEMORY as u32,
         }
     }
 }
 
-/// Load blind state from the FlipperFormat state file.
-///
-/// Returns a fresh empty state if the file doesn't exist, is corrupt, or has
-/// the wrong header. Never panics — just returns what it can, like a cat
-/// knocking things off a shelf and walking away.
-pub fn load_state() -> SomfyState {
-    let mut state = SomfyState::new();
-
-    unsafe {
-        let storage = flipperzero_sys::furi_record_open(c"storage".as_ptr())
-            as *mut flipperzero_sys::Storage;
-        let ff = flipperzero_sys::flipper_format_file_alloc(storage);
-
-        'load: {
-            // Open existing file
-            if !flipperzero_sys::flipper_format_file_open_existing(
-                ff,
-                STATE_PATH.as_ptr(),
-            ) {
-                break 'load;
-            }
+/// A file open on the SD card — every read and write the app makes goes
+/// through one. The file is closed, and the storage record let go, on drop.
+struct OpenFile {
+    file: *mut flipperzero_sys::File,
+    /// Cleared by `close`, so dropping doesn't close it twice.
+    open: bool,
+}
+
+impl OpenFile {
+    /// Open `path` with the firmware's access and open modes, e.g. `FSAM_READ`
+    /// and `FSOM_OPEN_EXISTING`.
+    fn open(
+        path: &CStr,
+        access: flipperzero_sys::FS_AccessMode,
+        mode: flipperzero_sys::FS_OpenMode,
+    ) -> Option<Self> {
+        unsafe {
+            let storage = flipperzero_sys::furi_record_open(c"storage".as_ptr())
+                as *mut flipperzero_sys::Storage;
+            let mut file = Self { file: flipperzero_sys::storage_file_alloc(storage), open: false };
+            file.open = flipperzero_sys::storage_file_open(file.file, path.as_ptr(), access, mode);
+            file.open.then_some(file)
+        }
+    }
+
+    /// Open `path` to read from the start.
+    fn read_only(path: &CStr) -> Option<Self> {
+        Self::open(path, flipperzero_sys::FSAM_READ, flipperzero_sys::FSOM_OPEN_EXISTING)
+    }
 
-            // Read and validate header
-            let filetype = flipperzero_sys::furi_string_alloc();
-            let mut version: u32 = 0;
-            if !flipperzero_sys::flipper_format_read_header(ff, filetype, &mut version) {
-                flipperzero_sys::furi_string_free(filetype);
-                break 'load;
+    /// Fill as much of `buf` as the file has left; 0 once it's all read.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        unsafe {
+            flipperzero_sys::storage_file_read(
+                self.file,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+            )
+        }
+    }
+
+    /// Write all of `bytes`. False if the card took less.
+    fn write(&mut self, bytes: &[u8]) -> bool {
+        let written = unsafe {
+            flipperzero_sys::storage_file_write(
+                self.file,
+                bytes.as_ptr() as *const c_void,
+                bytes.len(),
+            )
+        };
+        written == bytes.len()
+    }
+
+    fn size(&self) -> u64 {
+        unsafe { flipperzero_sys::storage_file_size(self.file) }
+    }
+
+    /// Move to `position`. False unless that's where it ended up.
+    fn seek(&mut self, position: usize) -> bool {
+        unsafe {
+            flipperzero_sys::storage_file_seek(self.file, position as u32, true)
+                && flipperzero_sys::storage_file_tell(self.file) == position as u64
+        }
+    }
+
+    /// Close the file. False if the last of it couldn't be stored.
+    fn close(mut self) -> bool {
+        self.open = false;
+        unsafe { flipperzero_sys::storage_file_close(self.file) }
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        unsafe {
+            if self.open {
+                flipperzero_sys::storage_file_close(self.file);
             }
-            if flipperzero_sys::furi_string_cmp_str(filetype, STATE_FILETYPE.as_ptr()) != 0
-                || version != STATE_VERSION
-            {
-                flipperzero_sys::furi_string_free(filetype);
-                break 'load;
+            flipperzero_sys::storage_file_free(self.file);
+            flipperzero_sys::furi_record_close(c"storage".as_ptr());
+        }
+    }
+}
+
+/// The Flipper's SD card, as `state_file` sees it.
+pub struct SdCard;
+
+impl SdCard {
+    /// Run `f` with the storage record held open.
+    fn with_storage<T>(f: impl FnOnce(*mut flipperzero_sys::Storage) -> T) -> T {
+        unsafe {
+            let storage = flipperzero_sys::furi_record_open(c"storage".as_ptr())
+                as *mut flipperzero_sys::Storage;
+            let result = f(storage);
+            flipperzero_sys::furi_record_close(c"storage".as_ptr());
+            result
+        }
+    }
+
+    /// Open `path` for writing as `mode` says, creating its folder on a fresh
+    /// card, and store whatever `contents` writes. True only once all of it is.
+    fn write_with(
+        &mut self,
+        path: &str,
+        mode: flipperzero_sys::FS_OpenMode,
+        contents: impl FnOnce(&mut dyn Write) -> fmt::Result,
+    ) -> bool {
+        let dir = c_path(path.rsplit_once('/').map_or("", |(dir, _)| dir));
+        let made_dir = Self::with_storage(|storage| unsafe {
+            flipperzero_sys::storage_simply_mkdir(storage, dir.as_ptr())
+        });
+        if !made_dir {
+            return false;
+        }
+        let Some(mut file) = OpenFile::open(&c_path(path), flipperzero_sys::FSAM_WRITE, mode)
+        else {
+            return false;
+        };
+
+        let mut out = FileWriter { file: &mut file, buf: [0; 128], len: 0 };
+        let written = contents(&mut out).is_ok() && out.flush();
+        file.close() && written
+    }
+
+    /// Add whatever `contents` writes to the end of the file, starting it if
+    /// there's none yet.
+    fn append(&mut self, path: &str, contents: impl FnOnce(&mut dyn Write) -> fmt::Result) -> bool {
+        self.write_with(path, flipperzero_sys::FSOM_OPEN_APPEND, contents)
+    }
+
+    /// The file's size in bytes, or `None` if it won't open.
+    fn size(&mut self, path: &str) -> Option<u64> {
+        OpenFile::read_only(&c_path(path)).map(|file| file.size())
+    }
+}
+
+/// A path as the firmware wants it. Ours never hold a NUL, but an empty one
+/// just names nothing.
+fn c_path(path: &str) -> CString {
+    CString::new(path).unwrap_or_default()
+}
+
+/// A file on the SD card, read a chunk at a time and handed out a line at a time.
+pub struct FileLines {
+    file: OpenFile,
+    chunk: [u8; 64],
+    /// Bytes of `chunk` holding data, and how many of those are used up.
+    chunk_len: usize,
+    chunk_pos: usize,
+    line: alloc::string::String,
+    /// File offset of the next byte to be handed out.
+    position: usize,
+    /// Set when a seek didn't land, after which `position` can't be trusted
+    /// and the file reads as ended.
+    lost: bool,
+}
+
+impl FileLines {
+    /// Open `path` for reading.
+    fn open(path: &CStr) -> Option<Self> {
+        Some(Self {
+            file: OpenFile::read_only(path)?,
+            chunk: [0; 64],
+            chunk_len: 0,
+            chunk_pos: 0,
+            line: alloc::string::String::new(),
+            position: 0,
+            lost: false,
+        })
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        if self.chunk_pos == self.chunk_len {
+            self.chunk_len = self.file.read(&mut self.chunk);
+            self.chunk_pos = 0;
+            if self.chunk_len == 0 {
+                return None;
             }
-            flipperzero_sys::furi_string_free(filetype);
+        }
+        let byte = self.chunk[self.chunk_pos];
+        self.chunk_pos += 1;
+        self.position += 1;
+        Some(byte)
+    }
+}
 
-            // Read blind count
-            let mut count: u32 = 0;
-            if !flipperzero_sys::flipper_format_read_uint32(ff, c"Count".as_ptr(), &mut count, 1) {
-                break 'load;
+impl LineSource for FileLines {
+    fn advance(&mut self) -> bool {
+        if self.lost {
+            return false;
+        }
+        let mut bytes = core::mem::take(&mut self.line).into_bytes();
+        bytes.clear();
+        let mut any = false;
+        while let Some(byte) = self.next_byte() {
+            any = true;
+            if byte == b'\n' {
+                break;
             }
-            if count > MAX_BLINDS as u32 {
-                count = MAX_BLINDS as u32;
+            if bytes.len() < MAX_LINE_BYTES {
+                bytes.push(byte);
             }
+        }
+        // A line that isn't text can't be a key we know; read it as blank
+        self.line = alloc::string::String::from_utf8(bytes).unwrap_or_default();
+        any
+    }
+
+    fn line(&self) -> &str {
+        &self.line
+    }
+
+    fn tell(&self) -> usize {
+        self.position
+    }
+
+    fn seek(&mut self, position: usize) {
+        // Rewinds are a line or two at most, but simpler to go back to the card.
+        // Reading on from wherever it did end up would hand keys to the wrong
+        // blind; ending here fails the load as truncated if any are still due
+        self.lost |= !self.file.seek(position);
+        self.position = position;
+        self.chunk_len = 0;
+        self.chunk_pos = 0;
+    }
+}
+
+/// Text written to an open file, gathered into chunks so the card isn't
+/// asked to store a few bytes at a time.
+struct FileWriter<'a> {
+    file: &'a mut OpenFile,
+    buf: [u8; 128],
+    len: usize,
+}
 
-            // Read each blind's data — one kitty at a time
-            let name_str = flipperzero_sys::furi_string_alloc();
-            for _ in 0..count {
-                if !flipperzero_sys::flipper_format_read_string(ff, c"Name".as_ptr(), name_str) {
-                    break;
-                }
-                let mut address: u32 = 0;
-                if !flipperzero_sys::flipper_format_read_uint32(
-                    ff,
-                    c"Address".as_ptr(),
-                    &mut address,
-                    1,
-                ) {
-                    break;
-                }
-                let mut rolling_code: u32 = 0;
-                if !flipperzero_sys::flipper_format_read_uint32(
-                    ff,
-                    c"RollingCode".as_ptr(),
-                    &mut rolling_code,
-                    1,
-                ) {
-                    break;
-                }
-
-                // Convert FuriString -> &CStr -> &str -> heapless::String
-                let c_str = CStr::from_ptr(flipperzero_sys::furi_string_get_cstr(name_str));
-                if let Ok(name_rust) = c_str.to_str() {
-                    let mut name = String::<MAX_NAME_LEN>::new();
-                    // Truncate if the name is too long — better than losing the whole blind
-                    let _ = name.push_str(
-                        &name_rust[..name_rust.len().min(MAX_NAME_LEN)],
-                    );
-                    let blind = SomfyBlind {
-                        name,
-                        address,
-                        rolling_code: rolling_code as u16,
-                    };
-                    let _ = state.blinds.push(blind);
-                }
+impl FileWriter<'_> {
+    /// Write out whatever is gathered. False if the card took less than all of it.
+    fn flush(&mut self) -> bool {
+        let complete = self.file.write(&self.buf[..self.len]);
+        self.len = 0;
+        complete
+    }
+}
+
+impl Write for FileWriter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for &byte in text.as_bytes() {
+            if self.len == self.buf.len() && !self.flush() {
+                return Err(fmt::Error);
             }
-            flipperzero_sys::furi_string_free(name_str);
+            self.buf[self.len] = byte;
+            self.len += 1;
         }
+        Ok(())
+    }
+}
+
+impl FileSystem for SdCard {
+    type Lines = FileLines;
+
+    fn exists(&mut self, path: &str) -> bool {
+        let path = c_path(path);
+        Self::with_storage(|storage| unsafe {
+            flipperzero_sys::storage_file_exists(storage, path.as_ptr())
+        })
+    }
 
-        flipperzero_sys::flipper_format_free(ff);
-        flipperzero_sys::furi_record_close(c"storage".as_ptr());
+    fn open(&mut self, path: &str) -> Option<FileLines> {
+        FileLines::open(&c_path(path))
     }
 
-    state
+    fn write(
+        &mut self,
+        path: &str,
+        contents: impl FnOnce(&mut dyn Write) -> fmt::Result,
+    ) -> bool {
+        self.write_with(path, flipperzero_sys::FSOM_CREATE_ALWAYS, contents)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> bool {
+        let (from, to) = (c_path(from), c_path(to));
+        Self::with_storage(|storage| unsafe {
+            flipperzero_sys::storage_common_rename(storage, from.as_ptr(), to.as_ptr())
+                == flipperzero_sys::FSE_OK
+        })
+    }
+
+    fn remove(&mut self, path: &str) -> bool {
+        let path = c_path(path);
+        Self::with_storage(|storage| unsafe {
+            flipperzero_sys::storage_simply_remove(storage, path.as_ptr())
+        })
+    }
 }
 
-/// Save blind state to the FlipperFormat state file.
+/// Load blind state from the FlipperFormat state file.
+///
+/// An unreadable file is moved aside before the error comes back; see
+/// `state_file::load`. Never panics, like a cat landing on its feet.
+pub fn load_state() -> Result<SomfyState, LoadError> {
+    let loaded = state_file::load(&mut SdCard);
+    if let Err(error) = loaded {
+        flipperzero::error!("State file unreadable: {}", error.code());
+    }
+    loaded
+}
+
+/// The newest earlier save that reads back whole, if any.
+pub fn recover_state() -> Option<SomfyState> {
+    let recovered = state_file::recover(&mut SdCard);
+    if recovered.is_some() {
+        flipperzero::warn!("Recovered state from an earlier save");
+    }
+    recovered
+}
+
+/// Save blind state, keeping the last few saves as backups.
 ///
 /// Returns true on success, false if something went wrong (like a cat that
 /// refuses to sit where you want it to).
 pub fn save_state(state: &SomfyState) -> bool {
-    let mut success = false;
-
-    unsafe {
-        let storage = flipperzero_sys::furi_record_open(c"storage".as_ptr())
-            as *mut flipperzero_sys::Storage;
-        let ff = flipperzero_sys::flipper_format_file_alloc(storage);
-
-        'save: {
-            // Open (or create) the file — open_always truncates existing content
-            if !flipperzero_sys::flipper_format_file_open_always(
-                ff,
-                STATE_PATH.as_ptr(),
-            ) {
-                break 'save;
-            }
+    state_file::save(&mut SdCard, state)
+}
 
-            // Write header
-            if !flipperzero_sys::flipper_format_write_header_cstr(
-                ff,
-                STATE_FILETYPE.as_ptr(),
-                STATE_VERSION,
-            ) {
-                break 'save;
-            }
+/// Reserve the current rolling code of `state.blinds[index]` on the SD card,
+/// ahead of sending it.
+///
+/// Returns false if the reservation couldn't be written, in which case the
+/// code must not go out. While a block lasts this writes nothing at all.
+pub fn reserve_rolling_code(state: &mut SomfyState, index: usize) -> bool {
+    let block = state.code_block;
+    if !state.blinds[index].reserve_rolling_code(block) || save_state(state) {
+        return true;
+    }
+    // Not on the card, so not reserved — the next try writes it again
+    state.blinds[index].release_reservation();
+    false
+}
 
-            // Write blind count
-            let count: u32 = state.blinds.len() as u32;
-            if !flipperzero_sys::flipper_format_write_uint32(ff, c"Count".as_ptr(), &count, 1) {
-                break 'save;
-            }
+/// Load app settings, falling back to defaults for a missing file or unknown values.
+pub fn load_settings() -> Settings {
+    let mut settings = Settings::default();
+    let Some(bytes) = read_file(&c_path(SETTINGS_PATH), MAX_SETTINGS_FILE_BYTES) else {
+        return settings;
+    };
+    let Ok(text) = core::str::from_utf8(&bytes) else {
+        return settings;
+    };
 
-            // Write each blind — herding cats, but in a loop
-            let mut all_ok = true;
-            for blind in state.blinds.iter() {
-                // Build a null-terminated name buffer.
-                // heapless::String doesn't include a null terminator, so we need
-                // a scratch buffer that's one byte larger — room for the \0 catnap.
-                let mut name_buf = [0u8; MAX_NAME_LEN + 1];
-                let name_bytes = blind.name.as_bytes();
-                let len = name_bytes.len().min(MAX_NAME_LEN);
-                name_buf[..len].copy_from_slice(&name_bytes[..len]);
-                // name_buf[len] is already 0 from initialization
-
-                if !flipperzero_sys::flipper_format_write_string_cstr(
-                    ff,
-                    c"Name".as_ptr(),
-                    name_buf.as_ptr() as *const c_char,
-                ) {
-                    all_ok = false;
-                    break;
-                }
-
-                let address = blind.address;
-                if !flipperzero_sys::flipper_format_write_uint32(
-                    ff,
-                    c"Address".as_ptr(),
-                    &address,
-                    1,
-                ) {
-                    all_ok = false;
-                    break;
-                }
-
-                let rolling_code = blind.rolling_code as u32;
-                if !flipperzero_sys::flipper_format_write_uint32(
-                    ff,
-                    c"RollingCode".as_ptr(),
-                    &rolling_code,
-                    1,
-                ) {
-                    all_ok = false;
-                    break;
-                }
-            }
+    let mut ff = Reader::new(text);
+    if ff.read_header(SETTINGS_FILETYPE) != Some(SETTINGS_VERSION) {
+        return settings;
+    }
 
-            if !all_ok {
-                break 'save;
-            }
+    if let Some(radio) = ff.optional(|ff| ff.read_string("Radio").map(RadioDevice::parse)) {
+        settings.radio = radio.unwrap_or_default();
+    }
 
-            success = true;
-        }
+    // Older settings files stop after the radio; keep the defaults then
+    let lbt = &mut settings.listen_before_talk;
+    if let Some(enabled) = ff.optional(|ff| ff.read_bool("ListenBeforeTalk")) {
+        lbt.enabled = enabled;
+    }
+    if let Some(threshold_dbm) = ff.optional(|ff| ff.read_int32("LbtThreshold")) {
+        lbt.threshold_dbm = threshold_dbm;
+    }
+    if let Some(max_wait_ms) = ff.optional(|ff| ff.read_uint32("LbtMaxWait")) {
+        lbt.max_wait_ms = max_wait_ms;
+    }
+    if let Some(format) = ff.optional(|ff| ff.read_string("StateFormat").map(StateFormat::parse)) {
+        settings.state_format = format.unwrap_or_default();
+    }
+    if let Some(bytes) = ff.optional(|ff| ff.read_uint32("BlindMemory")) {
+        settings.blind_memory = bytes;
+    }
+
+    settings
+}
+
+/// Save app settings. Returns true on success.
+pub fn save_settings(settings: &Settings) -> bool {
+    SdCard.replace(SETTINGS_PATH, |out| write_settings(out, settings))
+}
+
+/// Write out every setting.
+fn write_settings(out: impl Write, settings: &Settings) -> fmt::Result {
+    let lbt = settings.listen_before_talk;
+    let mut ff = Writer::new(out);
+    ff.write_header(SETTINGS_FILETYPE, SETTINGS_VERSION)?;
+    ff.write_string("Radio", settings.radio.encode())?;
+    ff.write_bool("ListenBeforeTalk", lbt.enabled)?;
+    ff.write_int32("LbtThreshold", &[lbt.threshold_dbm])?;
+    ff.write_uint32("LbtMaxWait", &[lbt.max_wait_ms])?;
+    ff.write_string("StateFormat", settings.state_format.encode())?;
+    // Only ever set by editing the file, so say what the number means
+    ff.write_comment("Heap the blinds may use, in bytes")?;
+    ff.write_uint32("BlindMemory", &[settings.blind_memory])
+}
+
+/// Append a line about a transmission to the TX log, stamped with the RTC time.
+///
+/// A full log is moved aside to `tx.log.old` first, replacing the previous one.
+pub fn log_transmission(
+    frame: SomfyFrame,
+    frequency: u32,
+    device: RadioDevice,
+    result: Result<(), TxError>,
+) -> bool {
+    let now = unsafe {
+        let mut now: flipperzero_sys::DateTime = core::mem::zeroed();
+        flipperzero_sys::furi_hal_rtc_get_datetime(&mut now);
+        now
+    };
+    let entry = TxLogEntry {
+        time: Timestamp {
+            year: now.year,
+            month: now.month,
+            day: now.day,
+            hour: now.hour,
+            minute: now.minute,
+            second: now.second,
+        },
+        frame,
+        frequency,
+        device,
+        result,
+    };
+
+    let mut card = SdCard;
+    if card.size(TX_LOG_PATH).is_some_and(|size| size >= MAX_TX_LOG_BYTES) {
+        card.rename(TX_LOG_PATH, TX_LOG_OLD_PATH);
+    }
+    card.append(TX_LOG_PATH, |mut out| tx_log::write_entry(&mut out, &entry))
+}
 
-        flipperzero_sys::flipper_format_free(ff);
-        flipperzero_sys::furi_record_close(c"storage".as_ptr());
+/// Read a whole file into memory, refusing anything larger than `max_len`.
+pub fn read_file(path: &CStr, max_len: usize) -> Option<alloc::vec::Vec<u8>> {
+    let mut file = OpenFile::read_only(path)?;
+    let size = file.size();
+    if size > max_len as u64 {
+        return None;
+    }
+    let mut contents = alloc::vec![0u8; size as usize];
+    (file.read(&mut contents) == contents.len()).then_some(contents)
+}
+
+/// Write a file for the stock Sub-GHz app, a piece at a time as `contents`
+/// produces it, creating its folder on a fresh card.
+///
+/// Like every other save it goes by way of a `.tmp` file, so a failed export
+/// never leaves half a file under the real name. Returns true only if all of
+/// it made it to the file.
+pub fn export_file(path: &str, contents: impl FnOnce(&mut dyn Write) -> fmt::Result) -> bool {
+    SdCard.replace(path, contents)
+}
+
+/// Why a `Somfy Telis` key file couldn't be imported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportError {
+    /// The file couldn't be read (or is far too big to be a key).
+    Read,
+    /// The file isn't a valid `Somfy Telis` key.
+    Parse(ParseError),
+    /// No room left in the blind memory budget.
+    Full,
+    /// A blind already has the key's address.
+    DuplicateAddress,
+}
+
+/// Export `command` from `blind` as a stock Sub-GHz `Somfy Telis` key file.
+///
+/// The key carries the blind's current rolling code. Returns the path written,
+/// or `None` for extended-frame blinds (which the protocol can't describe) and
+/// write failures.
+pub fn export_telis_key(blind: &SomfyBlind, command: SomfyCommand) -> Option<String<48>> {
+    let path = sub_file::key_path(blind.address);
+    let frame = blind.frame(command);
+    export_file(&path, |mut out| {
+        sub_file::write_telis_key(&mut out, blind.frequency, blind.preset, frame)
+    })
+    .then_some(path)
+}
+
+/// Import a stock Sub-GHz `Somfy Telis` key file as a new blind.
+///
+/// The blind carries on from the key's address, counter and radio settings, and is named
+/// after the file, e.g. `Living Room.sub` becomes "Living Room".
+pub fn import_telis_key(state: &mut SomfyState, path: &CStr) -> Result<(), ImportError> {
+    if state.is_full() {
+        return Err(ImportError::Full);
+    }
+
+    let bytes = read_file(path, MAX_KEY_FILE_BYTES).ok_or(ImportError::Read)?;
+    let text = core::str::from_utf8(&bytes).map_err(|_| ImportError::Parse(ParseError::BadKey))?;
+    let key = sub_file::parse_telis_key(text).map_err(ImportError::Parse)?;
+
+    let file_name = path.to_str().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    let stem = file_name.strip_suffix(".sub").unwrap_or(file_name);
+    let mut name = String::<MAX_NAME_LEN>::new();
+    // Truncate long file names at a char boundary rather than failing the import
+    for c in stem.chars() {
+        if name.push(c).is_err() {
+            break;
+        }
     }
 
-    success
+    let mut blind = SomfyBlind::continuing(name, key.frame);
+    blind.tune(key.frequency, key.preset);
+    state.add(blind).map_err(|error| match error {
+        AddError::Full => ImportError::Full,
+        AddError::DuplicateAddress => ImportError::DuplicateAddress,
+    })
 }
@@ -0,0 +1,441 @@
+//! Flipper Sub-GHz `.sub` files — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! Writes Somfy transmissions as RAW captures the stock Sub-GHz app can replay,
+//! just like the original JS app did, and reads captures of real remotes back
+//! in. Also speaks the stock app's own `Somfy Telis` key files, so a blind can
+//! move between the two apps. Every blind gets its own litter box :3
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::ffi::CStr;
+use core::fmt::{self, Write};
+
+use crate::protocol::{FrameLength, LevelDuration, SomfyAddress, SomfyFrame};
+use crate::radio::Preset;
+
+/// File type header the stock Sub-GHz app expects for RAW captures.
+pub const RAW_FILETYPE: &str = "Flipper SubGhz RAW File";
+
+/// RAW file format version.
+pub const RAW_VERSION: u32 = 1;
+
+/// The stock app never puts more than 512 values on one `RAW_Data` line.
+pub const MAX_VALUES_PER_LINE: usize = 512;
+
+/// File type header of decoded-protocol saves in the stock Sub-GHz app.
+pub const KEY_FILETYPE: &str = "Flipper SubGhz Key File";
+
+/// Key file format version.
+pub const KEY_VERSION: u32 = 1;
+
+/// The stock app's name for the 56-bit Somfy RTS protocol.
+pub const SOMFY_TELIS_PROTOCOL: &str = "Somfy Telis";
+
+/// Folder the stock Sub-GHz app browses by default.
+pub const EXPORT_DIR: &CStr = c"/ext/subghz";
+
+/// `EXPORT_DIR` for building paths in.
+const EXPORT_DIR_STR: &str = match EXPORT_DIR.to_str() {
+    Ok(dir) => dir,
+    Err(_) => panic!("EXPORT_DIR is not UTF-8"),
+};
+
+/// Write a transmission as a `Flipper SubGhz RAW File`.
+///
+/// Highs become positive durations and lows negative ones, split into
+/// `RAW_Data` lines of at most `MAX_VALUES_PER_LINE` values.
+pub fn write_raw<W: Write>(
+    out: &mut W,
+    frequency: u32,
+    preset: Preset,
+    timings: impl IntoIterator<Item = LevelDuration>,
+) -> fmt::Result {
+    writeln!(out, "Filetype: {RAW_FILETYPE}")?;
+    writeln!(out, "Version: {RAW_VERSION}")?;
+    writeln!(out, "Frequency: {frequency}")?;
+    writeln!(out, "Preset: {}", preset.name())?;
+    writeln!(out, "Protocol: RAW")?;
+
+    let mut on_line = 0;
+    for timing in timings {
+        if on_line == 0 {
+            out.write_str("RAW_Data:")?;
+        }
+        let sign = if timing.level { "" } else { "-" };
+        write!(out, " {sign}{}", timing.duration)?;
+        on_line += 1;
+        if on_line == MAX_VALUES_PER_LINE {
+            out.write_char('\n')?;
+            on_line = 0;
+        }
+    }
+    if on_line > 0 {
+        out.write_char('\n')?;
+    }
+
+    Ok(())
+}
+
+/// Why a `.sub` file could not be read as a RAW capture.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseError {
+    /// Not a `Flipper SubGhz RAW File`, e.g. a capture the stock app already decoded.
+    NotRaw,
+    /// A `RAW_Data` value that isn't a non-zero integer, on this 1-based line.
+    BadValue { line: usize },
+    /// The file has no `RAW_Data` values at all.
+    NoData,
+    /// Not a `Flipper SubGhz Key File` for the `Somfy Telis` protocol.
+    NotSomfyTelis,
+    /// A `Somfy Telis` key whose `Bit`/`Key` fields don't hold a valid 56-bit frame.
+    BadKey,
+}
+
+/// A RAW capture read back from a `.sub` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawCapture {
+    /// Carrier frequency in Hz, if the file says.
+    pub frequency: Option<u32>,
+    /// Modem preset, if the file names one we know.
+    pub preset: Option<Preset>,
+    /// Pulses in recording order, adjacent same-level values merged.
+    pub timings: Vec<LevelDuration>,
+}
+
+/// Parse a `Flipper SubGhz RAW File`.
+///
+/// The recorder sometimes writes one level as two values in a row (and always
+/// at line breaks when a level straddles them), so same-sign neighbours are
+/// merged into one pulse — the decoder expects levels to alternate.
+pub fn parse_raw(text: &str) -> Result<RawCapture, ParseError> {
+    let mut capture = RawCapture {
+        frequency: None,
+        preset: None,
+        timings: Vec::new(),
+    };
+    let mut is_raw = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "Filetype" => is_raw = value == RAW_FILETYPE,
+            "Frequency" => capture.frequency = value.parse().ok(),
+            "Preset" => capture.preset = Preset::parse(value),
+            "RAW_Data" => {
+                for number in value.split_ascii_whitespace() {
+                    let pulse = match number.parse::<i32>() {
+                        Ok(n) if n != 0 => LevelDuration {
+                            level: n > 0,
+                            duration: n.unsigned_abs(),
+                        },
+                        _ => return Err(ParseError::BadValue { line: index + 1 }),
+                    };
+                    match capture.timings.last_mut() {
+                        Some(last) if last.level == pulse.level => {
+                            last.duration += pulse.duration;
+                        }
+                        _ => capture.timings.push(pulse),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !is_raw {
+        return Err(ParseError::NotRaw);
+    }
+    if capture.timings.is_empty() {
+        return Err(ParseError::NoData);
+    }
+    Ok(capture)
+}
+
+/// Write a 56-bit frame as a stock `Somfy Telis` key file.
+///
+/// `Key` holds the frame as it goes on air (obfuscated), right-aligned in
+/// eight bytes like the stock decoder stores it. `Btn` and `Cnt` repeat the
+/// command nibble and rolling code for firmwares that read them directly.
+/// Extended frames have no `Somfy Telis` equivalent and are refused.
+pub fn write_telis_key<W: Write>(
+    out: &mut W,
+    frequency: u32,
+    preset: Preset,
+    frame: SomfyFrame,
+) -> fmt::Result {
+    if frame.length() != FrameLength::Standard {
+        return Err(fmt::Error);
+    }
+
+    writeln!(out, "Filetype: {KEY_FILETYPE}")?;
+    writeln!(out, "Version: {KEY_VERSION}")?;
+    writeln!(out, "Frequency: {frequency}")?;
+    writeln!(out, "Preset: {}", preset.name())?;
+    writeln!(out, "Protocol: {SOMFY_TELIS_PROTOCOL}")?;
+    writeln!(out, "Bit: {}", FrameLength::Standard.bits())?;
+    out.write_str("Key: 00")?;
+    for byte in frame.obfuscated() {
+        write!(out, " {byte:02X}")?;
+    }
+    out.write_char('\n')?;
+    writeln!(out, "Btn: {}", frame.command().nibble())?;
+    writeln!(out, "Cnt: {}", frame.rolling_code())?;
+    Ok(())
+}
+
+/// A stock `Somfy Telis` key read back from a `.sub` file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TelisKey {
+    /// Carrier frequency in Hz, if the file says.
+    pub frequency: Option<u32>,
+    /// Modem preset, if the file names one we know.
+    pub preset: Option<Preset>,
+    /// The last frame the remote sent.
+    pub frame: SomfyFrame,
+}
+
+/// Read a stock `Somfy Telis` key file.
+///
+/// `Key` is the source of truth — it is checksummed, `Btn` and `Cnt` are not —
+/// so those two are ignored.
+pub fn parse_telis_key(text: &str) -> Result<TelisKey, ParseError> {
+    let mut is_key = false;
+    let mut is_telis = false;
+    let mut frequency = None;
+    let mut preset = None;
+    let mut bits = None;
+    let mut key = None;
+
+    for line in text.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match field.trim() {
+            "Filetype" => is_key = value == KEY_FILETYPE,
+            "Protocol" => is_telis = value == SOMFY_TELIS_PROTOCOL,
+            "Frequency" => frequency = value.parse().ok(),
+            "Preset" => preset = Preset::parse(value),
+            "Bit" => bits = value.parse::<usize>().ok(),
+            "Key" => key = parse_key_bytes(value),
+            _ => {}
+        }
+    }
+
+    if !is_key || !is_telis {
+        return Err(ParseError::NotSomfyTelis);
+    }
+    let (Some(56), Some([0, air @ ..])) = (bits, key) else {
+        return Err(ParseError::BadKey);
+    };
+    let frame = SomfyFrame::deobfuscate(&air).map_err(|_| ParseError::BadKey)?;
+    Ok(TelisKey {
+        frequency,
+        preset,
+        frame,
+    })
+}
+
+/// Parse the eight space-separated hex bytes of a `Key` field.
+fn parse_key_bytes(value: &str) -> Option<[u8; 8]> {
+    let mut bytes = [0u8; 8];
+    let mut parts = value.split_ascii_whitespace();
+    for byte in &mut bytes {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(bytes)
+}
+
+/// Path for an exported frame, unique per remote and rolling code,
+/// e.g. `/ext/subghz/somfy_123456_0001.sub`.
+pub fn export_path(address: SomfyAddress, rolling_code: u16) -> heapless::String<48> {
+    let mut path = heapless::String::new();
+    // 48 bytes always fits the fixed-width name
+    let _ = write!(path, "{EXPORT_DIR_STR}/somfy_{:06X}_{rolling_code:04X}.sub", address.get());
+    path
+}
+
+/// Path for an exported `Somfy Telis` key, one per remote,
+/// e.g. `/ext/subghz/somfy_telis_123456.sub`.
+pub fn key_path(address: SomfyAddress) -> heapless::String<48> {
+    let mut path = heapless::String::new();
+    // 48 bytes always fits the fixed-width name
+    let _ = write!(path, "{EXPORT_DIR_STR}/somfy_telis_{:06X}.sub", address.get());
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+    use crate::protocol::{
+        KeyPolicy, SomfyCommand, SomfyFrame, SomfyTimings, build_transmission, decode_all,
+    };
+
+    #[test]
+    fn test_write_raw_matches_golden_file() {
+        let address = SomfyAddress::new(0x123456).unwrap();
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, address);
+        let timings = build_transmission(&frame, &SomfyTimings::DEFAULT, 4);
+
+        let mut out = String::new();
+        write_raw(&mut out, 433_420_000, Preset::Ook650Async, timings).unwrap();
+        assert_eq!(out, include_str!("../tests/fixtures/somfy_up_123456_0001.sub"));
+    }
+
+    #[test]
+    fn test_write_raw_splits_long_lines() {
+        let timings = (0..1100).map(|i| LevelDuration { level: i % 2 == 0, duration: 100 });
+
+        let mut out = String::new();
+        write_raw(&mut out, 433_420_000, Preset::Ook650Async, timings).unwrap();
+        let counts: Vec<usize> = out
+            .lines()
+            .filter_map(|line| line.strip_prefix("RAW_Data: "))
+            .map(|values| values.split(' ').count())
+            .collect();
+        assert_eq!(counts, [512, 512, 76]);
+    }
+
+    #[test]
+    fn test_parse_raw_round_trips_export() {
+        let address = SomfyAddress::new(0x123456).unwrap();
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, address);
+        let text = include_str!("../tests/fixtures/somfy_up_123456_0001.sub");
+        let capture = parse_raw(text).unwrap();
+        assert_eq!(capture.frequency, Some(433_420_000));
+        assert_eq!(capture.preset, Some(Preset::Ook650Async));
+        assert_eq!(capture.timings, build_transmission(&frame, &SomfyTimings::DEFAULT, 4));
+    }
+
+    #[test]
+    fn test_decode_captured_remote() {
+        // Two presses of a genuine remote: noise, jitter, a split level and
+        // values straddling RAW_Data lines
+        let text = include_str!("../tests/fixtures/capture_1a2b3c_up_down.sub");
+        let capture = parse_raw(text).unwrap();
+        let frames = decode_all(&capture.timings, &SomfyTimings::DEFAULT);
+
+        let summary: Vec<_> = frames
+            .iter()
+            .map(|f| (f.address().get(), f.command(), f.rolling_code(), f.key()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x1A2B3C, SomfyCommand::Up, 0x0041, 0xA1),
+                (0x1A2B3C, SomfyCommand::Down, 0x0042, 0xA2),
+            ]
+        );
+        let policy = KeyPolicy::infer(frames[0].key(), frames[0].rolling_code());
+        assert_eq!(policy, KeyPolicy::Rolling);
+    }
+
+    #[test]
+    fn test_parse_raw_merges_split_levels() {
+        let text = "Filetype: Flipper SubGhz RAW File\nRAW_Data: 100 200 -300\nRAW_Data: -50 400\n";
+        let timings = parse_raw(text).unwrap().timings;
+        assert_eq!(
+            timings,
+            [
+                LevelDuration { level: true, duration: 300 },
+                LevelDuration { level: false, duration: 350 },
+                LevelDuration { level: true, duration: 400 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_raw_errors() {
+        let keyed = "Filetype: Flipper SubGhz Key File\nProtocol: Somfy Telis\n";
+        assert_eq!(parse_raw(keyed), Err(ParseError::NotRaw));
+        assert_eq!(parse_raw(""), Err(ParseError::NotRaw));
+
+        let empty = "Filetype: Flipper SubGhz RAW File\nProtocol: RAW\n";
+        assert_eq!(parse_raw(empty), Err(ParseError::NoData));
+
+        let bad = "Filetype: Flipper SubGhz RAW File\nRAW_Data: 100 -200\nRAW_Data: 300 x\n";
+        assert_eq!(parse_raw(bad), Err(ParseError::BadValue { line: 3 }));
+        let zero = "Filetype: Flipper SubGhz RAW File\nRAW_Data: 100 0\n";
+        assert_eq!(parse_raw(zero), Err(ParseError::BadValue { line: 2 }));
+    }
+
+    #[test]
+    fn test_telis_key_matches_golden_file() {
+        // Same press as the rolling-key reference frames in protocol.rs
+        let address = SomfyAddress::new(0x1A2B3C).unwrap();
+        let frame = SomfyFrame::new(SomfyCommand::Up, 0x0041, address).with_key(0xA1);
+
+        let mut out = String::new();
+        write_telis_key(&mut out, 433_420_000, Preset::Ook650Async, frame).unwrap();
+        assert_eq!(out, include_str!("../tests/fixtures/somfy_telis_1a2b3c.sub"));
+    }
+
+    #[test]
+    fn test_telis_key_round_trip() {
+        let address = SomfyAddress::new(0xFEDCBA).unwrap();
+        for (command, rolling_code, key) in [
+            (SomfyCommand::My, 1, 0xA0),
+            (SomfyCommand::Down, 0x1237, 0xA7),
+            (SomfyCommand::Prog, 0xFFFF, 0x5C),
+        ] {
+            let frame = SomfyFrame::new(command, rolling_code, address).with_key(key);
+            let mut out = String::new();
+            write_telis_key(&mut out, 433_420_000, Preset::Ook650Async, frame).unwrap();
+            assert_eq!(parse_telis_key(&out).unwrap().frame, frame);
+        }
+    }
+
+    #[test]
+    fn test_telis_key_from_stock_app() {
+        // As saved by the stock app after decoding a remote: Bit and Key only
+        let text = include_str!("../tests/fixtures/somfy_telis_stock.sub");
+        let key = parse_telis_key(text).unwrap();
+        assert_eq!(key.frequency, Some(433_420_000));
+        assert_eq!(key.preset, Some(Preset::Ook650Async));
+        let frame = key.frame;
+        assert_eq!(frame.address().get(), 0x1A2B3C);
+        assert_eq!(frame.command(), SomfyCommand::Down);
+        assert_eq!(frame.rolling_code(), 0x0042);
+        assert_eq!(frame.key(), 0xA2);
+    }
+
+    #[test]
+    fn test_telis_key_rejects_extended_frames() {
+        let address = SomfyAddress::new(0x123456).unwrap();
+        let frame = SomfyFrame::new(SomfyCommand::Up, 1, address).with_extension([0; 3]);
+        let mut out = String::new();
+        assert!(write_telis_key(&mut out, 433_420_000, Preset::Ook650Async, frame).is_err());
+    }
+
+    #[test]
+    fn test_parse_telis_key_errors() {
+        let raw = include_str!("../tests/fixtures/somfy_up_123456_0001.sub");
+        assert_eq!(parse_telis_key(raw), Err(ParseError::NotSomfyTelis));
+
+        let header = "Filetype: Flipper SubGhz Key File\nProtocol: Somfy Telis\n";
+        let keytis = "Filetype: Flipper SubGhz Key File\nProtocol: Somfy Keytis\nBit: 80\n";
+        assert_eq!(parse_telis_key(keytis), Err(ParseError::NotSomfyTelis));
+        assert_eq!(parse_telis_key(header), Err(ParseError::BadKey));
+
+        let mut short = String::from(header);
+        short.push_str("Bit: 56\nKey: 00 A1 80 80 C1 DB F0\n");
+        assert_eq!(parse_telis_key(&short), Err(ParseError::BadKey));
+
+        let mut corrupt = String::from(header);
+        corrupt.push_str("Bit: 56\nKey: 00 A1 80 80 C1 DB F0 CD\n");
+        assert_eq!(parse_telis_key(&corrupt), Err(ParseError::BadKey));
+    }
+
+    #[test]
+    fn test_export_path() {
+        let address = SomfyAddress::new(0x10000A).unwrap();
+        assert_eq!(export_path(address, 0x1F), "/ext/subghz/somfy_10000A_001F.sub");
+        assert_eq!(key_path(address), "/ext/subghz/somfy_telis_10000A.sub");
+    }
+}
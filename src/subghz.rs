@@ -1,110 +1,326 @@
-//! Safe wrapper around the CC1101 Sub-GHz FFI for Somfy RTS transmission.
+//! Safe wrapper around the CC1101 Sub-GHz FFI for Somfy RTS transmission and reception.
 //!
-//! Uses heap allocation for timing buffers (just like the C version uses malloc),
-//! because they're too large for the Flipper's limited stack. Meow~
+//! Timings are streamed straight from the protocol encoder inside the TX
+//! callback — no buffers at all, so long holds cost no RAM. On the way in,
+//! the capture ISR drops pulses into a ring buffer for the app to lap up.
+//! Talks through the internal CC1101 or an external board on GPIO. Meow~
 
-extern crate alloc;
-
-use alloc::vec::Vec;
+use alloc::boxed::Box;
 use core::ffi::c_void;
+use core::ops::ControlFlow;
 use core::ptr;
 
 use flipperzero_sys as sys;
 
-use crate::protocol::{self, SomfyCommand};
+use crate::protocol::LevelDuration;
+use crate::pulse_ring::PulseRing;
+use crate::radio::{ListenBeforeTalk, Preset, PulseSource, RadioBackend, RadioDevice, TxError};
+
+/// Pulses buffered between the capture ISR and the app — a few Somfy frames' worth.
+const RX_RING_LEN: usize = 1024;
 
+/// How often the blocking RX loop checks back on the radio.
+const POLL_INTERVAL_MS: u32 = 10;
 
-/// Somfy RTS frequency: 433.42 MHz
-const SOMFY_FREQUENCY_HZ: u32 = 433_420_000;
+/// How long RSSI takes to settle after switching to receive.
+const RSSI_SETTLE_MS: u32 = 2;
 
-/// TX context passed to the yield callback via a raw pointer.
-struct TxContext {
-    timings: *const sys::LevelDuration,
-    count: usize,
-    index: usize,
+/// Convert our level/duration pair to the flipperzero-sys bitfield one.
+fn to_sys(timing: LevelDuration) -> sys::LevelDuration {
+    let level: u8 = if timing.level { 1 } else { 0 };
+    sys::LevelDuration {
+        _bitfield_align_1: [],
+        _bitfield_1: sys::LevelDuration::new_bitfield_1(timing.duration, level),
+    }
 }
 
 /// Yield callback invoked by the Sub-GHz hardware from interrupt context.
+///
+/// Pulls the next pulse from the pulse source — for the protocol encoder that
+/// is pure arithmetic, safe to run in an ISR.
 unsafe extern "C" fn tx_yield_callback(context: *mut c_void) -> sys::LevelDuration {
-    let ctx = unsafe { &mut *(context as *mut TxContext) };
-
-    if ctx.index < ctx.count {
-        let timing = unsafe { *ctx.timings.add(ctx.index) };
-        ctx.index += 1;
-        timing
-    } else {
-        // Signal end of transmission
-        sys::LevelDuration {
-            _bitfield_align_1: [],
-            _bitfield_1: sys::LevelDuration::new_bitfield_1(0, 0),
-        }
+    let pulses = unsafe { &mut *(context as *mut PulseSource) };
+
+    // A zero duration signals end of transmission
+    to_sys(pulses.next().unwrap_or(LevelDuration { level: false, duration: 0 }))
+}
+
+/// Capture callback invoked by the Sub-GHz hardware from interrupt context.
+///
+/// Only pushes into the ring — lock-free and allocation-free, safe in an ISR.
+unsafe extern "C" fn rx_capture_callback(level: bool, duration: u32, context: *mut c_void) {
+    let ring = unsafe { &*(context as *const PulseRing<RX_RING_LEN>) };
+    ring.push(LevelDuration { level, duration });
+}
+
+/// Sub-GHz device API name of a radio.
+fn device_name(device: RadioDevice) -> &'static core::ffi::CStr {
+    match device {
+        RadioDevice::Internal => c"cc1101_int",
+        RadioDevice::External => c"cc1101_ext",
     }
 }
 
-/// Transmit a Somfy RTS command over the CC1101 internal Sub-GHz radio.
+/// Whether an external CC1101 is plugged in and answering.
 ///
-/// Returns `true` on success, `false` on failure.
-pub fn transmit(command: SomfyCommand, rolling_code: u16, address: u32, repeats: u8) -> bool {
-    // Build protocol timings (pure Rust, on stack — heapless::Vec is fine here)
-    let proto_timings = protocol::build_transmission(command, rolling_code, address, repeats);
-    if proto_timings.is_empty() {
-        return false;
-    }
-
-    // Convert to sys::LevelDuration on the HEAP (too large for stack)
-    let sys_timings: Vec<sys::LevelDuration> = proto_timings
-        .iter()
-        .map(|t| {
-            let level: u8 = if t.level { 1 } else { 0 };
-            sys::LevelDuration {
-                _bitfield_align_1: [],
-                _bitfield_1: sys::LevelDuration::new_bitfield_1(t.duration, level),
-            }
-        })
-        .collect();
+/// The board is powered from the 5V pin, so it is switched on for the check
+/// (and back off, unless something else had it on).
+fn external_connected() -> bool {
+    unsafe {
+        let otg_was_enabled = sys::furi_hal_power_is_otg_enabled();
+        if !otg_was_enabled {
+            sys::furi_hal_power_enable_otg();
+        }
 
-    let mut tx_ctx = TxContext {
-        timings: sys_timings.as_ptr(),
-        count: sys_timings.len(),
-        index: 0,
-    };
+        let device = sys::subghz_devices_get_by_name(device_name(RadioDevice::External).as_ptr());
+        let connected = !device.is_null() && sys::subghz_devices_is_connect(device);
 
-    let mut success = false;
+        if !otg_was_enabled {
+            sys::furi_hal_power_disable_otg();
+        }
+        connected
+    }
+}
 
-    unsafe {
-        sys::subghz_devices_init();
+/// Check for an external CC1101 without keeping the radio claimed.
+pub fn probe_external() -> bool {
+    unsafe { sys::subghz_devices_init() };
+    let connected = external_connected();
+    unsafe { sys::subghz_devices_deinit() };
+    connected
+}
 
-        let device = sys::subghz_devices_get_by_name(c"cc1101_int".as_ptr());
+/// A CC1101 radio — the Flipper's own or an external board — driven through
+/// the Sub-GHz device API.
+pub struct Cc1101 {
+    /// The radio the user asked for.
+    preferred: RadioDevice,
+    /// The radio the current (or last) session actually used.
+    active: RadioDevice,
+    /// Set between `begin` and `end`.
+    device: *const sys::SubGhzDevice,
+    /// Whether `begin` switched on 5V for the external board.
+    powered_otg: bool,
+    /// The pulses on air, boxed again so the ISR gets a thin, stable pointer.
+    tx_source: Option<Box<PulseSource>>,
+    lbt: ListenBeforeTalk,
+    /// Whether `rssi` left the radio receiving.
+    sensing: bool,
+    /// What `set_frequency` last tuned to, for the region check on transmit.
+    frequency: u32,
+}
 
-        if !device.is_null() {
-            sys::subghz_devices_begin(device);
-            sys::subghz_devices_load_preset(
-                device,
-                sys::FuriHalSubGhzPresetOok650Async,
-                ptr::null_mut(),
-            );
-            sys::subghz_devices_set_frequency(device, SOMFY_FREQUENCY_HZ);
-
-            if sys::subghz_devices_set_tx(device) {
-                let callback_ptr = tx_yield_callback as *mut c_void;
-                let context_ptr = &mut tx_ctx as *mut TxContext as *mut c_void;
-
-                if sys::subghz_devices_start_async_tx(device, callback_ptr, context_ptr) {
-                    while !sys::subghz_devices_is_async_complete_tx(device) {
-                        sys::furi_delay_ms(10);
+impl Cc1101 {
+    /// Use `preferred`, falling back to the internal radio if it's missing.
+    pub fn new(preferred: RadioDevice) -> Self {
+        Self {
+            preferred,
+            active: preferred,
+            device: ptr::null(),
+            powered_otg: false,
+            tx_source: None,
+            lbt: ListenBeforeTalk::default(),
+            sensing: false,
+            frequency: 0,
+        }
+    }
+
+    /// Switch radios; takes effect at the next `begin`.
+    pub fn set_preferred(&mut self, preferred: RadioDevice) {
+        self.preferred = preferred;
+        self.active = preferred;
+    }
+
+    /// Turn listen-before-talk on or off, or change its limits.
+    pub fn set_listen_before_talk(&mut self, lbt: ListenBeforeTalk) {
+        self.lbt = lbt;
+    }
+
+    /// Start capturing into `ring`, which must outlive the capture.
+    fn start_rx(&mut self, ring: &PulseRing<RX_RING_LEN>) {
+        unsafe {
+            sys::subghz_devices_flush_rx(self.device);
+            sys::subghz_devices_set_rx(self.device);
+
+            let callback_ptr = rx_capture_callback as *mut c_void;
+            let context_ptr = ring as *const PulseRing<RX_RING_LEN> as *mut c_void;
+            sys::subghz_devices_start_async_rx(self.device, callback_ptr, context_ptr);
+        }
+    }
+
+    /// Switch 5V back off if `begin` switched it on.
+    fn release_otg(&mut self) {
+        if self.powered_otg {
+            unsafe { sys::furi_hal_power_disable_otg() };
+            self.powered_otg = false;
+        }
+    }
+
+    /// Stop capturing; after this the ISR no longer touches the ring.
+    fn stop_rx(&mut self) {
+        unsafe {
+            sys::subghz_devices_stop_async_rx(self.device);
+            sys::subghz_devices_idle(self.device);
+        }
+    }
+}
+
+impl RadioBackend for Cc1101 {
+    fn begin(&mut self) -> bool {
+        unsafe {
+            sys::subghz_devices_init();
+
+            self.active = RadioDevice::Internal;
+            if self.preferred == RadioDevice::External {
+                if external_connected() {
+                    self.powered_otg = !sys::furi_hal_power_is_otg_enabled();
+                    if self.powered_otg {
+                        sys::furi_hal_power_enable_otg();
                     }
-                    sys::subghz_devices_stop_async_tx(device);
-                    success = true;
+                    self.active = RadioDevice::External;
+                } else {
+                    flipperzero::warn!("External CC1101 not found, using internal");
                 }
             }
 
-            sys::subghz_devices_idle(device);
-            sys::subghz_devices_end(device);
+            let device = sys::subghz_devices_get_by_name(device_name(self.active).as_ptr());
+            if device.is_null() {
+                self.release_otg();
+                sys::subghz_devices_deinit();
+                return false;
+            }
+
+            sys::subghz_devices_begin(device);
+            self.device = device;
         }
+        true
+    }
+
+    fn device(&self) -> RadioDevice {
+        self.active
+    }
 
-        sys::subghz_devices_deinit();
+    fn fell_back(&self) -> bool {
+        self.active != self.preferred
     }
 
-    // sys_timings dropped here — after TX is complete
-    success
+    fn end(&mut self) {
+        unsafe {
+            sys::subghz_devices_idle(self.device);
+            sys::subghz_devices_end(self.device);
+            self.release_otg();
+            sys::subghz_devices_deinit();
+        }
+        self.device = ptr::null();
+        self.sensing = false;
+    }
+
+    fn set_frequency(&mut self, hz: u32) -> bool {
+        self.frequency = hz;
+        unsafe {
+            sys::subghz_devices_is_frequency_valid(self.device, hz)
+                && sys::subghz_devices_set_frequency(self.device, hz) != 0
+        }
+    }
+
+    fn load_preset(&mut self, preset: Preset) {
+        let preset = match preset {
+            Preset::Ook270Async => sys::FuriHalSubGhzPresetOok270Async,
+            Preset::Ook650Async => sys::FuriHalSubGhzPresetOok650Async,
+        };
+        unsafe { sys::subghz_devices_load_preset(self.device, preset, ptr::null_mut()) };
+    }
+
+    fn listen_before_talk(&self) -> ListenBeforeTalk {
+        self.lbt
+    }
+
+    fn rssi(&mut self) -> f32 {
+        unsafe {
+            if !self.sensing {
+                sys::subghz_devices_flush_rx(self.device);
+                sys::subghz_devices_set_rx(self.device);
+                // The CC1101's RSSI reading needs a moment to settle after entering RX
+                sys::furi_delay_ms(RSSI_SETTLE_MS);
+                self.sensing = true;
+            }
+            sys::subghz_devices_get_rssi(self.device)
+        }
+    }
+
+    fn start_tx(&mut self, pulses: PulseSource) -> Result<(), TxError> {
+        let mut source = Box::new(pulses);
+        unsafe {
+            if self.sensing {
+                sys::subghz_devices_idle(self.device);
+                self.sensing = false;
+            }
+            // `set_tx` refuses a region-locked frequency without saying why,
+            // so ask first to tell the user what's wrong
+            if !sys::furi_hal_region_is_frequency_allowed(self.frequency) {
+                return Err(TxError::RegionLocked(self.frequency));
+            }
+            if !sys::subghz_devices_set_tx(self.device) {
+                sys::subghz_devices_idle(self.device);
+                return Err(TxError::SetTxRefused);
+            }
+
+            let callback_ptr = tx_yield_callback as *mut c_void;
+            let context_ptr = &mut *source as *mut PulseSource as *mut c_void;
+            if !sys::subghz_devices_start_async_tx(self.device, callback_ptr, context_ptr) {
+                sys::subghz_devices_idle(self.device);
+                return Err(TxError::AsyncStartFailed);
+            }
+        }
+
+        self.tx_source = Some(source);
+        Ok(())
+    }
+
+    fn is_tx_complete(&mut self) -> bool {
+        unsafe { sys::subghz_devices_is_async_complete_tx(self.device) }
+    }
+
+    fn stop_tx(&mut self) {
+        unsafe {
+            if self.tx_source.is_some() {
+                sys::subghz_devices_stop_async_tx(self.device);
+            }
+            sys::subghz_devices_idle(self.device);
+        }
+        // Only now is the ISR done with the pulse source
+        self.tx_source = None;
+        self.sensing = false;
+    }
+
+    fn rx_stream(
+        &mut self,
+        timeout_ms: u32,
+        sink: &mut dyn FnMut(LevelDuration) -> ControlFlow<()>,
+    ) -> bool {
+        // About 4 KiB — twice the app's whole stack, so it lives on the heap
+        let ring = Box::new(PulseRing::<RX_RING_LEN>::new());
+        self.start_rx(&ring);
+
+        let start = unsafe { sys::furi_get_tick() };
+        let timeout = unsafe { sys::furi_ms_to_ticks(timeout_ms) };
+        'listen: loop {
+            while let Some(pulse) = ring.pop() {
+                if sink(pulse).is_break() {
+                    break 'listen;
+                }
+            }
+            if unsafe { sys::furi_get_tick() }.wrapping_sub(start) >= timeout {
+                break;
+            }
+            unsafe { sys::furi_delay_ms(POLL_INTERVAL_MS) };
+        }
+
+        self.stop_rx();
+        self.sensing = false;
+        if ring.take_overflow() {
+            flipperzero::warn!("RX fell behind, some pulses were lost");
+        }
+        true
+    }
 }
@@ -0,0 +1,107 @@
+//! TX log lines — pure Rust, no unsafe, no flipperzero imports.
+//!
+//! Every transmission leaves one line in a plain text file on the SD card,
+//! so a blind that "just didn't move" in the field can be diagnosed later
+//! from what the radio actually said. Paw prints in the snow :3
+
+use core::fmt::{self, Write};
+
+use crate::protocol::SomfyFrame;
+use crate::radio::{RadioDevice, TxError};
+
+/// A wall-clock time, as read from the RTC.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// What happened to one transmission.
+pub struct TxLogEntry {
+    pub time: Timestamp,
+    /// The frame that was (or would have been) sent.
+    pub frame: SomfyFrame,
+    pub frequency: u32,
+    /// The radio the transmission went through.
+    pub device: RadioDevice,
+    pub result: Result<(), TxError>,
+}
+
+/// Write `entry` as one log line, newline included, e.g.
+///
+/// `2026-10-17 18:04:09 addr=1A2B3C rc=0042 cmd=2 freq=433420000 radio=internal ok`
+pub fn write_entry(out: &mut impl Write, entry: &TxLogEntry) -> fmt::Result {
+    let t = entry.time;
+    write!(
+        out,
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} ",
+        t.year, t.month, t.day, t.hour, t.minute, t.second
+    )?;
+    write!(
+        out,
+        "addr={:06X} rc={:04X} cmd={:X} freq={} radio={} ",
+        entry.frame.address().get(),
+        entry.frame.rolling_code(),
+        entry.frame.command().nibble(),
+        entry.frequency,
+        entry.device.encode()
+    )?;
+    let error = match entry.result {
+        Ok(()) => return out.write_str("ok\n"),
+        Err(error) => error,
+    };
+    write!(out, "error={}", error.code())?;
+    if let TxError::RegionLocked(hz) = error {
+        write!(out, "({hz})")?;
+    }
+    out.write_str("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::string::String;
+
+    use super::*;
+    use crate::protocol::{SomfyAddress, SomfyCommand};
+
+    fn entry(result: Result<(), TxError>) -> TxLogEntry {
+        let address = SomfyAddress::new(0x1A2B3C).unwrap();
+        TxLogEntry {
+            time: Timestamp { year: 2026, month: 10, day: 7, hour: 8, minute: 4, second: 9 },
+            frame: SomfyFrame::new(SomfyCommand::Up, 0x42, address),
+            frequency: 433_420_000,
+            device: RadioDevice::External,
+            result,
+        }
+    }
+
+    fn line(result: Result<(), TxError>) -> String {
+        let mut out = String::new();
+        write_entry(&mut out, &entry(result)).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_success_line() {
+        assert_eq!(
+            line(Ok(())),
+            "2026-10-07 08:04:09 addr=1A2B3C rc=0042 cmd=2 freq=433420000 radio=external ok\n"
+        );
+    }
+
+    #[test]
+    fn test_error_lines() {
+        assert!(line(Err(TxError::Timeout)).ends_with(" radio=external error=timeout\n"));
+        assert!(line(Err(TxError::SetTxRefused)).ends_with(" error=set_tx_refused\n"));
+        assert!(
+            line(Err(TxError::RegionLocked(915_000_000)))
+                .ends_with(" error=region_locked(915000000)\n")
+        );
+    }
+}